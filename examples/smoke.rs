@@ -1,5 +1,4 @@
 extern crate lunar_lang;
-extern crate purua;
 
 fn main() {
     let source = r#"
@@ -13,12 +12,21 @@ end
         Ok(program) => {
             println!("Parsed program: {:?}", &program);
             let mut walker = lunar_lang::lua::walker::Walker::new();
-            walker.walk(&program.block);
+            if let Err(e) = walker.walk(&program.block) {
+                println!("Error compiling program: {}", e);
+                return;
+            }
             for (i, msg) in walker.msg_stack.iter().enumerate() {
                 println!("MSG: {:<04}: {:?}", i, msg);
             }
 
-            let mruby = lunar_lang::rite::transformer::transform(&walker.msg_stack);
+            let mruby = match lunar_lang::rite::transformer::transform(&walker.msg_stack) {
+                Ok(mruby) => mruby,
+                Err(e) => {
+                    println!("Error compiling program: {}", e);
+                    return;
+                },
+            };
             for (i, rep) in mruby.iter().enumerate() {
                 println!("IREP: {:<04}: rlen = {}", i, rep.borrow().rep_len);
                 println!("IREP: {:<04}: syms = {:?}", i, &rep.borrow().syms);
@@ -0,0 +1,54 @@
+use std::error::Error;
+use std::fmt;
+
+// Raised by the scanner and the parser
+#[derive(Debug)]
+pub struct SyntaxError {
+    pub message: String,
+    pub line: usize,
+}
+
+impl SyntaxError {
+    pub fn new(message: impl Into<String>, line: usize) -> Self {
+        Self {
+            message: message.into(),
+            line,
+        }
+    }
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for SyntaxError {}
+
+// Raised by the walker and the transformer for a program that parses but
+// cannot be compiled; the line is unknown once the IR has no tokens
+#[derive(Debug)]
+pub struct CompileError {
+    pub message: String,
+    pub line: Option<usize>,
+}
+
+impl CompileError {
+    pub fn new(message: impl Into<String>, line: Option<usize>) -> Self {
+        Self {
+            message: message.into(),
+            line,
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {}: {}", line, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl Error for CompileError {}
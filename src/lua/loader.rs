@@ -1,19 +1,23 @@
-use purua::parser::{ast, parser, stream::TokenStream};
+use super::{ast, parser, scanner::Scanner};
 
 #[derive(Debug)]
 pub struct LuaProgram {
     pub block: ast::Block,
 }
 
+// strings in the source are kept as bytes, so it need not be UTF-8
 pub fn load_file(path: &str) -> Result<LuaProgram, Box<dyn std::error::Error>> {
-    let source = std::fs::read_to_string(path)?;
-    load_string(source.as_str())
+    let source = std::fs::read(path)?;
+    load_bytes(&source)
 }
 
 pub fn load_string(source: &str) -> Result<LuaProgram, Box<dyn std::error::Error>> {
-    let mut scanner = purua::scanner::Scanner::new(source);
+    load_bytes(source.as_bytes())
+}
+
+pub fn load_bytes(source: &[u8]) -> Result<LuaProgram, Box<dyn std::error::Error>> {
+    let mut scanner = Scanner::new(source);
     scanner.scan()?;
-    let token_stream = TokenStream::new(scanner.tokens);
-    let block = parser::parse(token_stream)?;
+    let block = parser::parse(scanner.tokens)?;
     Ok(LuaProgram { block })
-}
\ No newline at end of file
+}
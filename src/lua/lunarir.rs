@@ -2,26 +2,109 @@
 pub enum LunarValue {
     Nil,
     Boolean(bool),
-    Number(f64),
+    // an integer in the range of LOADI32
+    Integer(i64),
     String(usize),
+    // a number in the pool
+    Literal(usize),
     // TODO: add more types
 }
 
+// Operators mapped directly onto mruby instructions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    // appends the right operand to the left one, which must be a String
+    Concat,
+}
+
+// Messages are consumed by rite::transformer as a stack machine:
+// values are pushed onto consecutive registers above the locals.
 #[derive(Debug, Clone)]
 pub enum LunarIR {
     ChunkStart(usize),
     ChunkEnd,
+    // binds the lowest unbound value on the stack to the local slot
     Local(usize),
-    ForStart(usize),
-    ForParam(usize, usize, usize),
-    ForEnd,
+    // unbinds the local slot and those bound after it, freeing their registers
+    FreeLocals(usize),
+    GetLocal(usize),
+    SetLocal(usize),
+    // a local slot of the irep `depth` levels above the parent
+    GetUpvar(usize, usize),
+    SetUpvar(usize, usize),
+    GetGlobal(usize),
+    GetConst(usize),
+    SetGlobal(usize),
+    // $_G ||= {}
+    InitGlobals(usize),
+    GetIndex,
+    // pops a table, a key and a value; a nil value removes the key by
+    // sending the symbol (`delete`)
+    SetIndex(usize),
+    Value(LunarValue),
+    // pops the right operand and replaces the left one with the result
+    BinOp(BinOp),
+    // the right operand is a small literal
+    AddI(u8),
+    SubI(u8),
+    // sends the symbol to the receiver below the arguments
+    Send(usize, usize),
+    Label(usize),
+    Jump(usize),
+    // conditional jumps test the top of the stack without popping it
+    JumpIf(usize),
+    JumpNot(usize),
+    JumpNil(usize),
+    // replaces a multiple value (an Array) with its first n values
+    Unpack(usize),
+    // replaces n values with an Array of them
+    Array(usize),
+    // replaces n values and an Array pushed after them with a new Array
+    // of all the values
+    Pack(usize),
+    // replaces n key/value pairs with a table of them
+    NewTable(usize),
+    // pops n key/value pairs into the table under them
+    TableAdd(usize),
+    // pushes a copy of the value n slots below the top
+    Copy(usize),
+    // pops the top and overwrites the value n slots below the new top
+    Store(usize),
+    Pop,
     Enter(u32),
     StoreSym(usize, String),
+    // pushes a lambda of the child irep
+    Lambda(usize),
+    // pops the value to return
+    Return,
+    // sends the symbol to self
     FunctionCallStart(usize),
-    FunctionCallArg(usize, LunarValue),
+    // sends the symbol to the value on the top
+    MethodCallStart(usize),
+    // sends the second symbol to the value on the top, or the first one
+    // to self when the value is nil
+    GlobalCallStart(usize, usize),
+    // the stack has the receiver, a function or a falsy value, and the
+    // receiver again: calls the function with the receiver as the first
+    // argument, or sends the first symbol to the receiver when it is falsy
+    SelfCallStart(usize, usize),
+    // the argument pushed last is an Array of all the arguments
+    SplatArgs,
+    // arguments are the values pushed after the call starts
     FunctionCallEnd,
-    PoolString(usize, String),
-    Block(usize),
+    PoolString(usize, Vec<u8>),
+    // an integer out of the range of LOADI32
+    PoolInteger(usize, i64),
+    PoolFloat(usize, f64),
     NoReturn,
     Stop,
-}
\ No newline at end of file
+}
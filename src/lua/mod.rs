@@ -1,3 +1,8 @@
+pub mod ast;
+pub mod errors;
 pub mod loader;
 pub mod lunarir;
-pub mod walker;
\ No newline at end of file
+pub mod parser;
+pub mod scanner;
+pub mod token;
+pub mod walker;
@@ -0,0 +1,633 @@
+use super::ast::*;
+use super::errors::SyntaxError;
+use super::token::{Token, TokenType};
+
+// Priority of unary operators, after lparser.c
+const UNARY_PRIORITY: u8 = 12;
+
+// (left, right) priorities of binary operators; right associative ones
+// bind tighter on the left
+fn binop_priority(token_type: TokenType) -> Option<(u8, u8)> {
+    use TokenType::*;
+    let priority = match token_type {
+        Or => (1, 1),
+        And => (2, 2),
+        Less | Greater | Le | Ge | Ne | Eql => (3, 3),
+        Bar => (4, 4),
+        Tilda => (5, 5),
+        Amp => (6, 6),
+        ShL | ShR => (7, 7),
+        Concat => (9, 8),
+        Plus | Minus => (10, 10),
+        Aster | Slash | IDiv | Perc => (11, 11),
+        Hat => (14, 13),
+        _ => return None,
+    };
+    Some(priority)
+}
+
+// `1.8p1` of `0x1.8p1`: hexadecimal digits with an optional fraction,
+// scaled by a decimal power of two
+fn hex_float(hex: &str) -> Option<f64> {
+    let (digits, exponent) = match hex.find(['p', 'P']) {
+        Some(at) => (&hex[..at], hex[at + 1..].parse::<i32>().ok()?),
+        None => (hex, 0),
+    };
+    let (int, frac) = digits.split_once('.').unwrap_or((digits, ""));
+    if int.is_empty() && frac.is_empty() {
+        return None;
+    }
+    let mut mantissa = 0.0;
+    for c in int.chars().chain(frac.chars()) {
+        mantissa = mantissa * 16.0 + c.to_digit(16)? as f64;
+    }
+    let exponent = exponent.saturating_sub(4 * frac.len() as i32);
+    Some(mantissa * 2f64.powi(exponent))
+}
+
+type ParseResult<T> = Result<T, SyntaxError>;
+
+pub fn parse(tokens: Vec<Token>) -> ParseResult<Block> {
+    let mut parser = Parser::new(tokens);
+    let block = parser.block()?;
+    parser.expect(TokenType::Eof)?;
+    Ok(block)
+}
+
+#[derive(Debug)]
+pub struct Parser {
+    tokens: Vec<Token>,
+    current: usize,
+}
+
+impl Parser {
+    pub fn new(tokens: Vec<Token>) -> Self {
+        Self { tokens, current: 0 }
+    }
+
+    fn peek(&self) -> TokenType {
+        self.tokens[self.current].token_type
+    }
+
+    fn peek_next(&self) -> TokenType {
+        match self.tokens.get(self.current + 1) {
+            Some(token) => token.token_type,
+            None => TokenType::Eof,
+        }
+    }
+
+    fn advance(&mut self) -> Token {
+        let token = self.tokens[self.current].clone();
+        if token.token_type != TokenType::Eof {
+            self.current += 1;
+        }
+        token
+    }
+
+    fn test(&mut self, token_type: TokenType) -> bool {
+        if self.peek() == token_type {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token_type: TokenType) -> ParseResult<Token> {
+        if self.peek() == token_type {
+            Ok(self.advance())
+        } else {
+            Err(self.error(format!("{:?} expected", token_type)))
+        }
+    }
+
+    fn error(&self, message: impl Into<String>) -> SyntaxError {
+        let token = &self.tokens[self.current];
+        let message = format!("{} near {:?}", message.into(), token.lexeme);
+        SyntaxError::new(message, token.line)
+    }
+
+    fn block_follows(&self) -> bool {
+        use TokenType::*;
+        matches!(self.peek(), Eof | End | Else | Elseif | Until)
+    }
+
+    fn block(&mut self) -> ParseResult<Block> {
+        let mut stats = Vec::new();
+        let mut last_stat = None;
+        while !self.block_follows() {
+            if self.test(TokenType::SemiColon) {
+                continue;
+            }
+            if matches!(self.peek(), TokenType::Return | TokenType::Break) {
+                last_stat = Some(self.laststat()?);
+                self.test(TokenType::SemiColon);
+                break;
+            }
+            stats.push(self.stat()?);
+        }
+        Ok(Block(Chunk(stats, last_stat)))
+    }
+
+    fn laststat(&mut self) -> ParseResult<LastStat> {
+        if self.peek() == TokenType::Break {
+            return Ok(LastStat::Break(self.advance()));
+        }
+        self.expect(TokenType::Return)?;
+        if self.block_follows() || self.peek() == TokenType::SemiColon {
+            Ok(LastStat::Return(None))
+        } else {
+            Ok(LastStat::Return(Some(self.exprlist()?)))
+        }
+    }
+
+    fn stat(&mut self) -> ParseResult<Stat> {
+        use TokenType::*;
+        match self.peek() {
+            Do => {
+                self.advance();
+                let block = self.block()?;
+                self.expect(End)?;
+                Ok(Stat::Do(block))
+            },
+            While => {
+                self.advance();
+                let cond = self.expr()?;
+                self.expect(Do)?;
+                let block = self.block()?;
+                self.expect(End)?;
+                Ok(Stat::While(Box::new(cond), block))
+            },
+            Repeat => {
+                self.advance();
+                let block = self.block()?;
+                self.expect(Until)?;
+                let cond = self.expr()?;
+                Ok(Stat::Repeat(Box::new(cond), block))
+            },
+            If => self.stat_if(),
+            For => self.stat_for(),
+            Function => {
+                self.advance();
+                let func_name = self.funcname()?;
+                let func_body = self.funcbody()?;
+                Ok(Stat::Function(func_name, func_body))
+            },
+            Local => {
+                self.advance();
+                if self.test(Function) {
+                    let name = self.expect(Name)?;
+                    let func_body = self.funcbody()?;
+                    Ok(Stat::LocalFunction(name, func_body))
+                } else {
+                    let names = self.namelist()?;
+                    let exprs = if self.test(Assign) {
+                        Some(self.exprlist()?)
+                    } else {
+                        None
+                    };
+                    Ok(Stat::LocalDeclVar(names, exprs))
+                }
+            },
+            Goto => {
+                self.advance();
+                let name = self.expect(Name)?;
+                Ok(Stat::Goto(name))
+            },
+            DbColon => {
+                self.advance();
+                let name = self.expect(Name)?;
+                self.expect(DbColon)?;
+                Ok(Stat::Label(name))
+            },
+            _ => self.stat_expr(),
+        }
+    }
+
+    fn stat_if(&mut self) -> ParseResult<Stat> {
+        self.expect(TokenType::If)?;
+        let cond = self.expr()?;
+        self.expect(TokenType::Then)?;
+        let block = self.block()?;
+
+        let mut elseifs = Vec::new();
+        while self.test(TokenType::Elseif) {
+            let cond = self.expr()?;
+            self.expect(TokenType::Then)?;
+            elseifs.push((Box::new(cond), self.block()?));
+        }
+        let else_block = if self.test(TokenType::Else) {
+            Some(self.block()?)
+        } else {
+            None
+        };
+        self.expect(TokenType::End)?;
+        Ok(Stat::If(Box::new(cond), block, elseifs, else_block))
+    }
+
+    fn stat_for(&mut self) -> ParseResult<Stat> {
+        self.expect(TokenType::For)?;
+        if self.peek_next() == TokenType::Assign {
+            let name = self.expect(TokenType::Name)?;
+            self.expect(TokenType::Assign)?;
+            let start = self.expr()?;
+            self.expect(TokenType::Comma)?;
+            let limit = self.expr()?;
+            let step = if self.test(TokenType::Comma) {
+                Some(Box::new(self.expr()?))
+            } else {
+                None
+            };
+            self.expect(TokenType::Do)?;
+            let block = self.block()?;
+            self.expect(TokenType::End)?;
+            Ok(Stat::For(name, Box::new(start), Box::new(limit), step, block))
+        } else {
+            let names = self.namelist()?;
+            self.expect(TokenType::In)?;
+            let exprs = self.exprlist()?;
+            self.expect(TokenType::Do)?;
+            let block = self.block()?;
+            self.expect(TokenType::End)?;
+            Ok(Stat::ForIn(names, exprs, block))
+        }
+    }
+
+    // a function call or an assignment, both starting with a prefixexp
+    fn stat_expr(&mut self) -> ParseResult<Stat> {
+        let prefix = self.suffixedexp()?;
+        if matches!(self.peek(), TokenType::Assign | TokenType::Comma) {
+            let mut vars = vec![self.to_var(prefix)?];
+            while self.test(TokenType::Comma) {
+                let prefix = self.suffixedexp()?;
+                vars.push(self.to_var(prefix)?);
+            }
+            self.expect(TokenType::Assign)?;
+            let exprs = self.exprlist()?;
+            Ok(Stat::Assign(VarList(vars), exprs))
+        } else {
+            match prefix {
+                PrefixExp::PrefixCall(call) => Ok(Stat::FunctionCall(call)),
+                _ => Err(self.error("syntax error")),
+            }
+        }
+    }
+
+    fn to_var(&self, prefix: PrefixExp) -> ParseResult<Var> {
+        match prefix {
+            PrefixExp::PrefixVar(var) => Ok(*var),
+            _ => Err(self.error("cannot assign to this expression")),
+        }
+    }
+
+    fn funcname(&mut self) -> ParseResult<FuncName> {
+        let mut names = vec![self.expect(TokenType::Name)?];
+        while self.test(TokenType::Period) {
+            names.push(self.expect(TokenType::Name)?);
+        }
+        let method = if self.test(TokenType::Colon) {
+            Some(self.expect(TokenType::Name)?)
+        } else {
+            None
+        };
+        Ok(FuncName(names, method))
+    }
+
+    fn funcbody(&mut self) -> ParseResult<FuncBody> {
+        self.expect(TokenType::ParenL)?;
+        let mut names = Vec::new();
+        let mut vararg = false;
+        if self.peek() != TokenType::ParenR {
+            loop {
+                if self.test(TokenType::Dots) {
+                    vararg = true;
+                    break;
+                }
+                names.push(self.expect(TokenType::Name)?);
+                if !self.test(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.expect(TokenType::ParenR)?;
+        let block = self.block()?;
+        self.expect(TokenType::End)?;
+        Ok(FuncBody(ParamList(NameList(names), vararg), block))
+    }
+
+    fn namelist(&mut self) -> ParseResult<NameList> {
+        let mut names = vec![self.expect(TokenType::Name)?];
+        while self.test(TokenType::Comma) {
+            names.push(self.expect(TokenType::Name)?);
+        }
+        Ok(NameList(names))
+    }
+
+    fn exprlist(&mut self) -> ParseResult<ExprList> {
+        let mut exprs = vec![self.expr()?];
+        while self.test(TokenType::Comma) {
+            exprs.push(self.expr()?);
+        }
+        Ok(ExprList(exprs))
+    }
+
+    pub fn expr(&mut self) -> ParseResult<Expr> {
+        self.subexpr(0)
+    }
+
+    // parses operators binding tighter than the limit
+    fn subexpr(&mut self, limit: u8) -> ParseResult<Expr> {
+        let mut lhs = match self.peek() {
+            TokenType::Not | TokenType::Minus | TokenType::Opus | TokenType::Tilda => {
+                let op = self.advance();
+                let operand = self.subexpr(UNARY_PRIORITY)?;
+                Expr::Unop(Unop(op), Box::new(operand))
+            },
+            _ => self.simpleexp()?,
+        };
+
+        while let Some((left, right)) = binop_priority(self.peek()) {
+            if left <= limit {
+                break;
+            }
+            let op = self.advance();
+            let rhs = self.subexpr(right)?;
+            lhs = Expr::ExprBinop(Box::new(lhs), Binop(op), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn simpleexp(&mut self) -> ParseResult<Expr> {
+        use TokenType::*;
+        let expr = match self.peek() {
+            Nil => Expr::Nil,
+            True => Expr::True,
+            False => Expr::False,
+            Dots => return Ok(Expr::Dots(self.advance())),
+            Int | Float => {
+                let token = self.advance();
+                return self.number(&token);
+            },
+            StringLit => return Ok(Expr::String(self.advance().bytes)),
+            Function => {
+                self.advance();
+                return Ok(Expr::Function(super::ast::Function(self.funcbody()?)));
+            },
+            BraceL => return Ok(Expr::TableConstructor(self.tableconstructor()?)),
+            _ => return Ok(Expr::PrefixExp(self.suffixedexp()?)),
+        };
+        self.advance();
+        Ok(expr)
+    }
+
+    fn number(&self, token: &Token) -> ParseResult<Expr> {
+        let lexeme = token.lexeme.as_str();
+        let parsed = match lexeme.strip_prefix("0x").or_else(|| lexeme.strip_prefix("0X")) {
+            Some(hex) if token.token_type == TokenType::Float => hex_float(hex).map(Expr::Float),
+            // hexadecimal integers wrap around as in Lua
+            Some(hex) if !hex.is_empty() => hex
+                .chars()
+                .try_fold(0u64, |n, c| c.to_digit(16).map(|d| n.wrapping_mul(16).wrapping_add(d as u64)))
+                .map(|n| Expr::Integer(n as i64)),
+            Some(_) => None,
+            // a decimal integer too large for an integer is a float
+            None if token.token_type == TokenType::Int => match lexeme.parse::<i64>() {
+                Ok(n) => Some(Expr::Integer(n)),
+                Err(_) => lexeme.parse::<f64>().ok().map(Expr::Float),
+            },
+            None => lexeme.parse::<f64>().ok().map(Expr::Float),
+        };
+        parsed.ok_or_else(|| SyntaxError::new(format!("malformed number near {:?}", lexeme), token.line))
+    }
+
+    fn primaryexp(&mut self) -> ParseResult<PrefixExp> {
+        match self.peek() {
+            TokenType::Name => {
+                let name = self.advance();
+                Ok(PrefixExp::PrefixVar(Box::new(Var::VarName(name))))
+            },
+            TokenType::ParenL => {
+                self.advance();
+                let expr = self.expr()?;
+                self.expect(TokenType::ParenR)?;
+                Ok(PrefixExp::PrefixParen(Box::new(expr)))
+            },
+            _ => Err(self.error("unexpected symbol")),
+        }
+    }
+
+    // primaryexp { `.´ Name | `[´ exp `]´ | `:´ Name args | args }
+    fn suffixedexp(&mut self) -> ParseResult<PrefixExp> {
+        use TokenType::*;
+        let mut prefix = self.primaryexp()?;
+        loop {
+            prefix = match self.peek() {
+                Period => {
+                    self.advance();
+                    let name = self.expect(Name)?;
+                    PrefixExp::PrefixVar(Box::new(Var::VarMember(prefix, name)))
+                },
+                BracketL => {
+                    self.advance();
+                    let index = self.expr()?;
+                    self.expect(BracketR)?;
+                    PrefixExp::PrefixVar(Box::new(Var::VarIdx(prefix, Box::new(index))))
+                },
+                Colon => {
+                    self.advance();
+                    let name = self.expect(Name)?;
+                    let args = self.args()?;
+                    PrefixExp::PrefixCall(FunctionCall(Box::new(prefix), Some(name), args))
+                },
+                ParenL | BraceL | StringLit => {
+                    let args = self.args()?;
+                    PrefixExp::PrefixCall(FunctionCall(Box::new(prefix), None, args))
+                },
+                _ => return Ok(prefix),
+            };
+        }
+    }
+
+    fn args(&mut self) -> ParseResult<Args> {
+        match self.peek() {
+            TokenType::StringLit => Ok(Args::ArgsString(self.advance().bytes)),
+            TokenType::BraceL => Ok(Args::ArgsTable(self.tableconstructor()?)),
+            TokenType::ParenL => {
+                self.advance();
+                let exprs = if self.peek() == TokenType::ParenR {
+                    ExprList(Vec::new())
+                } else {
+                    self.exprlist()?
+                };
+                self.expect(TokenType::ParenR)?;
+                Ok(Args::ArgsList(exprs))
+            },
+            _ => Err(self.error("function arguments expected")),
+        }
+    }
+
+    fn tableconstructor(&mut self) -> ParseResult<TableConstructor> {
+        self.expect(TokenType::BraceL)?;
+        let mut fields = Vec::new();
+        while self.peek() != TokenType::BraceR {
+            fields.push(self.field()?);
+            if !self.test(TokenType::Comma) && !self.test(TokenType::SemiColon) {
+                break;
+            }
+        }
+        self.expect(TokenType::BraceR)?;
+        Ok(TableConstructor(FieldList(fields)))
+    }
+
+    fn field(&mut self) -> ParseResult<Field> {
+        match (self.peek(), self.peek_next()) {
+            (TokenType::BracketL, _) => {
+                self.advance();
+                let index = self.expr()?;
+                self.expect(TokenType::BracketR)?;
+                self.expect(TokenType::Assign)?;
+                let value = self.expr()?;
+                Ok(Field::AssignIdx(Box::new(index), Box::new(value)))
+            },
+            (TokenType::Name, TokenType::Assign) => {
+                let name = self.advance();
+                self.advance();
+                let value = self.expr()?;
+                Ok(Field::AssignName(name, Box::new(value)))
+            },
+            _ => Ok(Field::UniExp(Box::new(self.expr()?))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lua::scanner::Scanner;
+
+    fn parse_source(source: &str) -> ParseResult<Block> {
+        let mut scanner = Scanner::new(source.as_bytes());
+        scanner.scan()?;
+        parse(scanner.tokens)
+    }
+
+    // the expression of `return <source>`
+    fn parse_expr(source: &str) -> Expr {
+        let block = parse_source(&format!("return {}", source)).unwrap();
+        match block.0 .1 {
+            Some(LastStat::Return(Some(ExprList(mut exprs)))) => exprs.remove(0),
+            other => panic!("not a return: {:?}", other),
+        }
+    }
+
+    // the expression with every operation in parentheses
+    fn show(expr: &Expr) -> String {
+        match expr {
+            Expr::Integer(n) => n.to_string(),
+            Expr::Float(n) => format!("{:?}", n),
+            Expr::String(bytes) => format!("{:?}", String::from_utf8_lossy(bytes)),
+            Expr::PrefixExp(PrefixExp::PrefixVar(var)) => match var.as_ref() {
+                Var::VarName(name) => name.lexeme.clone(),
+                other => panic!("unexpected var: {:?}", other),
+            },
+            Expr::PrefixExp(PrefixExp::PrefixParen(expr)) => show(expr),
+            Expr::ExprBinop(lhs, op, rhs) => format!("({} {} {})", show(lhs), op.0.lexeme, show(rhs)),
+            Expr::Unop(op, expr) => format!("({} {})", op.0.lexeme, show(expr)),
+            other => panic!("unexpected expression: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn binary_operators_follow_lua_precedence() {
+        assert_eq!(show(&parse_expr("1 + 2 * 3 - 4")), "((1 + (2 * 3)) - 4)");
+        assert_eq!(show(&parse_expr("a or b and c == d")), "(a or (b and (c == d)))");
+        assert_eq!(show(&parse_expr("a < b .. c + d")), "(a < (b .. (c + d)))");
+        assert_eq!(show(&parse_expr("a | b ~ c & d << e")), "(a | (b ~ (c & (d << e))))");
+        assert_eq!(show(&parse_expr("a // b % c")), "((a // b) % c)");
+    }
+
+    #[test]
+    fn power_and_concat_are_right_associative() {
+        assert_eq!(show(&parse_expr("a ^ b ^ c")), "(a ^ (b ^ c))");
+        assert_eq!(show(&parse_expr("a .. b .. c")), "(a .. (b .. c))");
+    }
+
+    #[test]
+    fn unary_operators_bind_tighter_than_binary_but_not_power() {
+        assert_eq!(show(&parse_expr("-a ^ 2")), "(- (a ^ 2))");
+        assert_eq!(show(&parse_expr("not a == b")), "((not a) == b)");
+        assert_eq!(show(&parse_expr("#a + 1")), "((# a) + 1)");
+        assert_eq!(show(&parse_expr("2 ^ -a")), "(2 ^ (- a))");
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(show(&parse_expr("42")), "42");
+        assert_eq!(show(&parse_expr("0xff")), "255");
+        // hexadecimal integers wrap around
+        assert_eq!(show(&parse_expr("0xffffffffffffffff")), "-1");
+        // a decimal integer too large for an integer is a float
+        assert_eq!(show(&parse_expr("9223372036854775808")), "9.223372036854776e18");
+        assert_eq!(show(&parse_expr("3.0")), "3.0");
+        assert_eq!(show(&parse_expr(".5")), "0.5");
+        assert_eq!(show(&parse_expr("1e2")), "100.0");
+        assert_eq!(show(&parse_expr("2E-1")), "0.2");
+        assert_eq!(show(&parse_expr("0x1p4")), "16.0");
+        assert_eq!(show(&parse_expr("0xA.8")), "10.5");
+        assert_eq!(show(&parse_expr("0x1.8P-1")), "0.75");
+        assert_eq!(show(&parse_expr("0x.1")), "0.0625");
+    }
+
+    #[test]
+    fn malformed_numbers_are_errors() {
+        assert!(parse_source("return 3x").is_err());
+        assert!(parse_source("return 0x").is_err());
+        assert!(parse_source("return 0x1p").is_err());
+        assert!(parse_source("return 1e").is_err());
+    }
+
+    #[test]
+    fn statements() {
+        let source = "
+            local a, b = 1
+            a, b = b, a
+            f(a)
+            do end
+            while a do break end
+            repeat until b
+            if a then elseif b then else end
+            for i = 1, 10, 2 do end
+            for k, v in pairs(t) do end
+            function m.n:o() end
+            local function g(...) end
+            goto done
+            ::done::
+            return a;
+        ";
+        let Chunk(stats, last) = parse_source(source).unwrap().0;
+        assert_eq!(stats.len(), 13);
+        assert!(matches!(&stats[0], Stat::LocalDeclVar(NameList(names), Some(ExprList(exprs))) if names.len() == 2 && exprs.len() == 1));
+        assert!(matches!(&stats[1], Stat::Assign(VarList(vars), ExprList(exprs)) if vars.len() == 2 && exprs.len() == 2));
+        assert!(matches!(&stats[2], Stat::FunctionCall(_)));
+        assert!(matches!(&stats[3], Stat::Do(_)));
+        assert!(matches!(&stats[4], Stat::While(_, Block(Chunk(_, Some(LastStat::Break(_)))))));
+        assert!(matches!(&stats[5], Stat::Repeat(..)));
+        assert!(matches!(&stats[6], Stat::If(_, _, elseifs, Some(_)) if elseifs.len() == 1));
+        assert!(matches!(&stats[7], Stat::For(name, _, _, Some(_), _) if name.lexeme == "i"));
+        assert!(matches!(&stats[8], Stat::ForIn(NameList(names), ExprList(exprs), _) if names.len() == 2 && exprs.len() == 1));
+        assert!(matches!(&stats[9], Stat::Function(FuncName(path, Some(method)), _) if path.len() == 2 && method.lexeme == "o"));
+        assert!(matches!(&stats[10], Stat::LocalFunction(name, FuncBody(ParamList(_, true), _)) if name.lexeme == "g"));
+        assert!(matches!(&stats[11], Stat::Goto(name) if name.lexeme == "done"));
+        assert!(matches!(&stats[12], Stat::Label(name) if name.lexeme == "done"));
+        assert!(matches!(last, Some(LastStat::Return(Some(_)))));
+    }
+
+    #[test]
+    fn statement_errors() {
+        assert!(parse_source("x = = 1").is_err());
+        assert!(parse_source("return 1 x = 2").is_err());
+        assert!(parse_source("if a then").is_err());
+        assert!(parse_source("f() = 1").is_err());
+        assert!(parse_source("local function a.b() end").is_err());
+    }
+}
@@ -0,0 +1,502 @@
+use super::errors::SyntaxError;
+use super::token::{Token, TokenType};
+
+#[derive(Debug)]
+pub struct Scanner<'source> {
+    pub source: &'source [u8],
+    pub tokens: Vec<Token>,
+    start: usize,
+    current: usize,
+    line: usize,
+}
+
+impl<'source> Scanner<'source> {
+    pub fn new(source: &'source [u8]) -> Self {
+        Self {
+            source,
+            tokens: Vec::new(),
+            start: 0,
+            current: 0,
+            line: 1,
+        }
+    }
+
+    pub fn scan(&mut self) -> Result<usize, SyntaxError> {
+        while !self.is_at_end() {
+            self.start = self.current;
+            self.scan_token()?;
+        }
+
+        self.tokens.push(Token::new(TokenType::Eof, "", self.line));
+        Ok(self.tokens.len())
+    }
+
+    fn scan_token(&mut self) -> Result<(), SyntaxError> {
+        use TokenType::*;
+        let c = self.advance();
+        match c {
+            b'(' => self.push_token(ParenL),
+            b')' => self.push_token(ParenR),
+            b'{' => self.push_token(BraceL),
+            b'}' => self.push_token(BraceR),
+            b'[' => match self.long_bracket() {
+                Some(level) => {
+                    let content = self.long_string(level, "unfinished long string")?;
+                    self.tokens.push(Token::string(content, self.line));
+                },
+                None if self.peek() == b'=' => {
+                    return Err(self.error("invalid long string delimiter"));
+                },
+                None => self.push_token(BracketL),
+            },
+            b']' => self.push_token(BracketR),
+            b',' => self.push_token(Comma),
+            b'.' => {
+                if self.test(b'.') {
+                    let tok = if self.test(b'.') { Dots } else { Concat };
+                    self.push_token(tok);
+                } else if self.peek().is_ascii_digit() {
+                    self.number()?;
+                } else {
+                    self.push_token(Period);
+                }
+            },
+            b'-' => {
+                if self.test(b'-') {
+                    // comment: --[[ ... ]] or -- up to the end of the line
+                    if self.test(b'[') {
+                        if let Some(level) = self.long_bracket() {
+                            self.long_string(level, "unfinished long comment")?;
+                            return Ok(());
+                        }
+                    }
+                    while !matches!(self.peek(), b'\n' | b'\r') && !self.is_at_end() {
+                        self.advance();
+                    }
+                } else {
+                    self.push_token(Minus);
+                }
+            },
+            b'+' => self.push_token(Plus),
+            b'&' => self.push_token(Amp),
+            b'|' => self.push_token(Bar),
+            b'%' => self.push_token(Perc),
+            b'^' => self.push_token(Hat),
+            b';' => self.push_token(SemiColon),
+            b'*' => self.push_token(Aster),
+            b'#' => self.push_token(Opus),
+            b':' => {
+                let tok = if self.test(b':') { DbColon } else { Colon };
+                self.push_token(tok);
+            },
+            b'~' => {
+                let tok = if self.test(b'=') { Ne } else { Tilda };
+                self.push_token(tok);
+            },
+            b'=' => {
+                let tok = if self.test(b'=') { Eql } else { Assign };
+                self.push_token(tok);
+            },
+            b'<' => {
+                let tok = if self.test(b'<') {
+                    ShL
+                } else if self.test(b'=') {
+                    Le
+                } else {
+                    Less
+                };
+                self.push_token(tok);
+            },
+            b'>' => {
+                let tok = if self.test(b'>') {
+                    ShR
+                } else if self.test(b'=') {
+                    Ge
+                } else {
+                    Greater
+                };
+                self.push_token(tok);
+            },
+            b'/' => {
+                let tok = if self.test(b'/') { IDiv } else { Slash };
+                self.push_token(tok);
+            },
+            b' ' | b'\t' | 0x0b | 0x0c => {
+                // Ignore whitespace.
+            },
+            b'\n' | b'\r' => self.newline(c),
+            b'\'' | b'"' => self.string(c)?,
+            c if c.is_ascii_digit() => self.number()?,
+            c if is_alpha(c) => self.name(),
+            c => {
+                return Err(self.error(format!("unexpected character: {:?}", c as char)));
+            },
+        }
+        Ok(())
+    }
+
+    fn string(&mut self, quote: u8) -> Result<(), SyntaxError> {
+        let mut buf = Vec::new();
+        loop {
+            if self.is_at_end() {
+                return Err(self.error("unfinished string"));
+            }
+            match self.advance() {
+                c if c == quote => break,
+                b'\n' | b'\r' => return Err(self.error("unfinished string")),
+                b'\\' => self.escape(&mut buf)?,
+                c => buf.push(c),
+            }
+        }
+
+        self.tokens.push(Token::string(buf, self.line));
+        Ok(())
+    }
+
+    // decodes the escape sequence after a backslash into raw bytes
+    fn escape(&mut self, buf: &mut Vec<u8>) -> Result<(), SyntaxError> {
+        let escaped = match self.advance() {
+            b'n' => b'\n',
+            b't' => b'\t',
+            b'r' => b'\r',
+            b'a' => 0x07,
+            b'b' => 0x08,
+            b'f' => 0x0c,
+            b'v' => 0x0b,
+            b'\\' => b'\\',
+            b'"' => b'"',
+            b'\'' => b'\'',
+            c @ (b'\n' | b'\r') => {
+                self.newline(c);
+                b'\n'
+            },
+            b'x' => {
+                let mut value = 0;
+                for _ in 0..2 {
+                    value = value * 16 + self.hex_digit()?;
+                }
+                value as u8
+            },
+            b'z' => {
+                // skips the following whitespace, line breaks included
+                while self.peek().is_ascii_whitespace() && !self.is_at_end() {
+                    let c = self.advance();
+                    if matches!(c, b'\n' | b'\r') {
+                        self.newline(c);
+                    }
+                }
+                return Ok(());
+            },
+            b'u' => {
+                if !self.test(b'{') {
+                    return Err(self.error("missing '{' in \\u{xxxx}"));
+                }
+                let mut value = self.hex_digit()?;
+                while self.peek().is_ascii_hexdigit() {
+                    value = value * 16 + self.hex_digit()?;
+                    if value > 0x7fff_ffff {
+                        return Err(self.error("UTF-8 value too large"));
+                    }
+                }
+                if !self.test(b'}') {
+                    return Err(self.error("missing '}' in \\u{xxxx}"));
+                }
+                buf.extend(utf8_escape(value));
+                return Ok(());
+            },
+            c if c.is_ascii_digit() => {
+                let mut value = (c - b'0') as u32;
+                for _ in 0..2 {
+                    if !self.peek().is_ascii_digit() {
+                        break;
+                    }
+                    value = value * 10 + (self.advance() - b'0') as u32;
+                }
+                if value > 0xff {
+                    return Err(self.error("decimal escape too large"));
+                }
+                value as u8
+            },
+            c => return Err(self.error(format!("invalid escape sequence: \\{}", c as char))),
+        };
+        buf.push(escaped);
+        Ok(())
+    }
+
+    fn hex_digit(&mut self) -> Result<u32, SyntaxError> {
+        match (self.peek() as char).to_digit(16) {
+            Some(digit) => {
+                self.advance();
+                Ok(digit)
+            },
+            _ => Err(self.error("hexadecimal digit expected")),
+        }
+    }
+
+    // after a `[`, the level of `[[` or `[==[`, or None for a plain bracket
+    fn long_bracket(&mut self) -> Option<usize> {
+        let level = self.source[self.current..].iter().take_while(|c| **c == b'=').count();
+        if self.source.get(self.current + level) != Some(&b'[') {
+            return None;
+        }
+        self.current += level + 1;
+        Some(level)
+    }
+
+    // the contents up to the closing bracket of the level, taken as they are
+    // except that a first line break is skipped and line breaks become \n
+    fn long_string(&mut self, level: usize, unfinished: &str) -> Result<Vec<u8>, SyntaxError> {
+        let mut buf = Vec::new();
+        if matches!(self.peek(), b'\n' | b'\r') {
+            let c = self.advance();
+            self.newline(c);
+        }
+        loop {
+            if self.is_at_end() {
+                return Err(self.error(unfinished));
+            }
+            match self.advance() {
+                b']' if self.closes_long_bracket(level) => {
+                    self.current += level + 1;
+                    return Ok(buf);
+                },
+                c @ (b'\n' | b'\r') => {
+                    self.newline(c);
+                    buf.push(b'\n');
+                },
+                c => buf.push(c),
+            }
+        }
+    }
+
+    fn closes_long_bracket(&self, level: usize) -> bool {
+        let rest = &self.source[self.current..];
+        rest.len() > level && rest[..level].iter().all(|c| *c == b'=') && rest[level] == b']'
+    }
+
+    // counts a line break, taking \r\n and \n\r as one
+    fn newline(&mut self, c: u8) {
+        let other = if c == b'\n' { b'\r' } else { b'\n' };
+        self.test(other);
+        self.line += 1;
+    }
+
+    // the lexeme is checked by the parser when converting the number
+    fn number(&mut self) -> Result<(), SyntaxError> {
+        let mut is_float = self.source[self.start] == b'.';
+        let hex = self.source[self.start] == b'0' && matches!(self.peek(), b'x' | b'X');
+        if hex {
+            self.advance();
+            // a hexadecimal float has a binary exponent after `p`
+            loop {
+                match self.peek() {
+                    c if c.is_ascii_hexdigit() => {},
+                    b'.' => is_float = true,
+                    b'p' | b'P' => {
+                        is_float = true;
+                        if matches!(self.peek_next(), b'+' | b'-') {
+                            self.advance();
+                        }
+                    },
+                    _ => break,
+                }
+                self.advance();
+            }
+        } else {
+            loop {
+                match self.peek() {
+                    c if c.is_ascii_digit() => {},
+                    b'.' => is_float = true,
+                    b'e' | b'E' => {
+                        is_float = true;
+                        if matches!(self.peek_next(), b'+' | b'-') {
+                            self.advance();
+                        }
+                    },
+                    _ => break,
+                }
+                self.advance();
+            }
+        }
+        if is_alpha(self.peek()) {
+            return Err(self.error("malformed number"));
+        }
+
+        let tok = if is_float { TokenType::Float } else { TokenType::Int };
+        self.push_token(tok);
+        Ok(())
+    }
+
+    fn name(&mut self) {
+        use TokenType::*;
+
+        while is_alphanumeric(self.peek()) {
+            self.advance();
+        }
+
+        let tok = match &self.source[self.start..self.current] {
+            b"and" => And,
+            b"break" => Break,
+            b"do" => Do,
+            b"else" => Else,
+            b"elseif" => Elseif,
+            b"end" => End,
+            b"false" => False,
+            b"for" => For,
+            b"function" => Function,
+            b"goto" => Goto,
+            b"if" => If,
+            b"in" => In,
+            b"local" => Local,
+            b"nil" => Nil,
+            b"not" => Not,
+            b"or" => Or,
+            b"repeat" => Repeat,
+            b"return" => Return,
+            b"then" => Then,
+            b"true" => True,
+            b"until" => Until,
+            b"while" => While,
+            _ => Name,
+        };
+
+        self.push_token(tok);
+    }
+
+    fn advance(&mut self) -> u8 {
+        let c = self.peek();
+        self.current += 1;
+        c
+    }
+
+    fn test(&mut self, expected: u8) -> bool {
+        if self.is_at_end() || self.peek() != expected {
+            return false;
+        }
+        self.current += 1;
+        true
+    }
+
+    fn peek(&self) -> u8 {
+        self.source.get(self.current).copied().unwrap_or(b'\0')
+    }
+
+    fn peek_next(&self) -> u8 {
+        self.source.get(self.current + 1).copied().unwrap_or(b'\0')
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.current >= self.source.len()
+    }
+
+    fn push_token(&mut self, token_type: TokenType) {
+        // tokens other than strings are ASCII
+        let lexeme = String::from_utf8_lossy(&self.source[self.start..self.current]);
+        self.tokens.push(Token::new(token_type, lexeme, self.line));
+    }
+
+    fn error(&self, message: impl Into<String>) -> SyntaxError {
+        SyntaxError::new(message, self.line)
+    }
+}
+
+// encodes the code point as UTF-8, extended up to 2^31 as Lua 5.4 does
+fn utf8_escape(mut value: u32) -> Vec<u8> {
+    if value < 0x80 {
+        return vec![value as u8];
+    }
+    let mut bytes = Vec::new();
+    // the largest value that still fits in the first byte
+    let mut first_max = 0x3f;
+    loop {
+        bytes.push(0x80 | (value & 0x3f) as u8);
+        value >>= 6;
+        first_max >>= 1;
+        if value <= first_max {
+            break;
+        }
+    }
+    bytes.push(((!first_max << 1) | value) as u8);
+    bytes.reverse();
+    bytes
+}
+
+fn is_alpha(c: u8) -> bool {
+    c.is_ascii_alphabetic() || c == b'_'
+}
+
+fn is_alphanumeric(c: u8) -> bool {
+    is_alpha(c) || c.is_ascii_digit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scan(source: &[u8]) -> Result<Vec<Token>, SyntaxError> {
+        let mut scanner = Scanner::new(source);
+        scanner.scan()?;
+        Ok(scanner.tokens)
+    }
+
+    // the bytes of the single string literal in the source
+    fn string(source: &[u8]) -> Vec<u8> {
+        let tokens = scan(source).unwrap();
+        assert_eq!(tokens.len(), 2, "{:?}", tokens);
+        tokens[0].bytes.clone()
+    }
+
+    #[test]
+    fn decimal_escapes() {
+        assert_eq!(string(br#""\65\066\0067""#), b"AB\x067");
+        assert_eq!(string(br#""\0\255""#), b"\x00\xff");
+        assert!(scan(br#""\256""#).is_err());
+    }
+
+    #[test]
+    fn hexadecimal_escapes() {
+        assert_eq!(string(br#""\x41\xfF""#), b"A\xff");
+        assert!(scan(br#""\x4""#).is_err());
+        assert!(scan(br#""\xg0""#).is_err());
+    }
+
+    #[test]
+    fn utf8_escapes() {
+        assert_eq!(string(br#""\u{48}\u{7FF}\u{FFFF}""#), "H\u{7ff}\u{ffff}".as_bytes());
+        // beyond U+FFFF and up to 2^31 as in Lua 5.4
+        assert_eq!(string(br#""\u{10FFFF}""#), b"\xf4\x8f\xbf\xbf");
+        assert_eq!(string(br#""\u{110000}""#), b"\xf4\x90\x80\x80");
+        assert_eq!(string(br#""\u{7FFFFFFF}""#), b"\xfd\xbf\xbf\xbf\xbf\xbf");
+        assert!(scan(br#""\u{80000000}""#).is_err());
+        assert!(scan(br#""\u{}""#).is_err());
+        assert!(scan(br#""\u48""#).is_err());
+    }
+
+    #[test]
+    fn z_escape_skips_whitespace() {
+        assert_eq!(string(b"\"a\\z  \n\t  b\""), b"ab");
+        let tokens = scan(b"\"a\\z\n\nb\" x").unwrap();
+        assert_eq!(tokens[1].line, 3);
+    }
+
+    #[test]
+    fn long_brackets() {
+        assert_eq!(string(b"[[a\\nb]]"), b"a\\nb");
+        // the first line break is skipped
+        assert_eq!(string(b"[==[\nfoo]]bar]=]\n]==]"), b"foo]]bar]=]\n");
+        assert_eq!(string(b"[[\n\nx]]"), b"\nx");
+        assert!(scan(b"[==[foo]=]").is_err());
+        assert!(scan(b"[=x").is_err());
+    }
+
+    #[test]
+    fn crlf_line_breaks() {
+        assert_eq!(string(b"[[\r\nx\r\ny]]"), b"x\ny");
+        assert_eq!(string(b"\"a\\\r\nb\""), b"a\nb");
+        let tokens = scan(b"a\r\nb\n\rc\r\rd").unwrap();
+        let lines: Vec<usize> = tokens.iter().map(|token| token.line).collect();
+        assert_eq!(lines, vec![1, 2, 3, 5, 5]);
+        let tokens = scan(b"-- comment\r\rx").unwrap();
+        assert_eq!(tokens[0].line, 3);
+    }
+}
@@ -0,0 +1,100 @@
+// Tokens of Lua, after purua's scanner (https://github.com/udzura/purua)
+#[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenType {
+    Opus = b'#' as u16,
+    Perc = b'%' as u16,
+    Amp = b'&' as u16,
+    ParenL = b'(' as u16,
+    ParenR = b')' as u16,
+    Aster = b'*' as u16,
+    Plus = b'+' as u16,
+    Comma = b',' as u16,
+    Minus = b'-' as u16,
+    Period = b'.' as u16,
+    Slash = b'/' as u16,
+    Colon = b':' as u16,
+    SemiColon = b';' as u16,
+    Less = b'<' as u16,
+    Assign = b'=' as u16,
+    Greater = b'>' as u16,
+    BracketL = b'[' as u16,
+    BracketR = b']' as u16,
+    Hat = b'^' as u16,
+    BraceL = b'{' as u16,
+    Bar = b'|' as u16,
+    BraceR = b'}' as u16,
+    Tilda = b'~' as u16,
+
+    And = 257,
+    Break,
+    Do,
+    Else,
+    Elseif,
+    End,
+    False,
+    For,
+    Function,
+    Goto,
+    If,
+    In,
+    Local,
+    Nil,
+    Not,
+    Or,
+    Repeat,
+    Return,
+    Then,
+    True,
+    Until,
+    While,
+
+    IDiv,
+    // `..`
+    Concat,
+    // `...`
+    Dots,
+    Eql,
+    Ge,
+    Le,
+    Ne,
+    ShL,
+    ShR,
+    DbColon,
+    Eof,
+
+    Float,
+    Int,
+    Name,
+    StringLit,
+}
+
+#[derive(Debug, Clone)]
+pub struct Token {
+    pub token_type: TokenType,
+    // the source text, or the contents of a string literal
+    pub lexeme: String,
+    // the exact contents of a string literal, which need not be UTF-8
+    pub bytes: Vec<u8>,
+    pub line: usize,
+}
+
+impl Token {
+    pub fn new(token_type: TokenType, lexeme: impl Into<String>, line: usize) -> Self {
+        Self {
+            token_type,
+            lexeme: lexeme.into(),
+            bytes: Vec::new(),
+            line,
+        }
+    }
+
+    pub fn string(bytes: Vec<u8>, line: usize) -> Self {
+        Self {
+            token_type: TokenType::StringLit,
+            lexeme: String::from_utf8_lossy(&bytes).into_owned(),
+            bytes,
+            line,
+        }
+    }
+}
@@ -1,38 +1,135 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use purua::{parser::ast::*, TokenType};
+use super::ast::*;
+use super::errors::CompileError;
+use super::token::{Token, TokenType};
 
 use super::lunarir::*;
 
+// How Lua globals are stored on the mruby side
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GlobalsMode {
+    // `x` becomes the mruby global variable `$x`
+    #[default]
+    GlobalVariable,
+    // `x` becomes `$_G["x"]`, a Hash shared by the whole script
+    GlobalTable,
+}
+
+// The Lua version whose number semantics are followed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LuaVersion {
+    // numbers have no integer subtype, so integral ones are Integers
+    Lua51,
+    // integers and floats are Integers and Floats, as from Lua 5.3 on
+    #[default]
+    Lua54,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CompileOptions {
+    pub globals: GlobalsMode,
+    pub version: LuaVersion,
+}
+
+// the longest string a pool entry can hold, its length being a u16
+const POOL_STRING_MAX: usize = u16::MAX as usize;
+
+// as LFIELDS_PER_FLUSH of lparser.c
+const FIELDS_PER_FLUSH: usize = 50;
+
 #[derive(Debug)]
 pub struct Walker {
     pub msg_stack: Vec<LunarIR>,
     pub idx_of_irep: usize,
     pub current_irep: usize,
     pub idx_of_ireps: HashMap<usize, IrepIndices>,
+    pub labels: usize,
+    pub options: CompileOptions,
+    // the first error found; walking goes on so that it is one of many
+    pub error: Option<CompileError>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct IrepIndices {
     pub locals: usize,
     pub syms: usize,
     pub pool: usize,
+    pub reps: usize,
+    // the enclosing function's irep, whose locals are upvalues here
+    pub parent: Option<usize>,
+    pub sym_table: HashMap<String, usize>,
+    pub pool_table: HashMap<Vec<u8>, usize>,
+    pub integer_table: HashMap<i64, usize>,
+    // floats by their bits
+    pub float_table: HashMap<u64, usize>,
+    // visible local names and their slots, innermost last
+    pub scope: Vec<(String, usize)>,
+    // local slots used by nested functions, whose registers are never reused
+    pub captured: HashSet<usize>,
+    // labels to jump to on `break`, innermost last
+    pub loop_exits: Vec<usize>,
+    // names used by closures in each enclosing loop, innermost last
+    pub loop_captures: Vec<HashSet<String>>,
+    // local slots holding a one-element Array with the value (see define_local)
+    pub boxed: HashSet<usize>,
+    // `::name::` labels of the enclosing blocks, innermost last
+    pub label_blocks: Vec<LabelBlock>,
+    // gotos whose label has not been reached yet
+    pub pending_gotos: Vec<PendingGoto>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LabelBlock {
+    // how many locals were visible when the block started
+    pub scope_len: usize,
+    pub labels: HashMap<String, GotoLabel>,
+}
+
+#[derive(Debug, Clone)]
+pub struct GotoLabel {
+    pub label: usize,
+    pub line: usize,
+    // nothing but labels follows it, so the locals of the block are out of scope
+    pub at_end: bool,
+    pub reached: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct PendingGoto {
+    pub label: usize,
+    pub name: String,
+    pub line: usize,
+    // how many locals of the label's block are visible at the goto
+    pub active: usize,
+}
+
+impl Default for Walker {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Walker {
     pub fn new() -> Self {
+        Self::with_options(CompileOptions::default())
+    }
+
+    pub fn with_options(options: CompileOptions) -> Self {
         Walker {
             msg_stack: Vec::new(),
             idx_of_irep: 0,
             current_irep: 0,
-            idx_of_ireps: HashMap::from([(
-                0,
-                IrepIndices {
-                    locals: 0,
-                    syms: 0,
-                    pool: 0,
-                },
-            )]),
+            idx_of_ireps: HashMap::from([(0, IrepIndices::default())]),
+            labels: 0,
+            options,
+            error: None,
+        }
+    }
+
+    fn error(&mut self, message: impl Into<String>, line: usize) {
+        if self.error.is_none() {
+            self.error = Some(CompileError::new(message, Some(line)));
         }
     }
 
@@ -40,8 +137,112 @@ impl Walker {
         self.msg_stack.push(msg);
     }
 
-    pub fn walk(&mut self, root: &Block) {
+    fn indices(&mut self) -> &mut IrepIndices {
+        self.idx_of_ireps.get_mut(&self.current_irep).unwrap()
+    }
+
+    pub fn intern_sym(&mut self, name: &str) -> usize {
+        if let Some(idx) = self.indices().sym_table.get(name) {
+            return *idx;
+        }
+        let indices = self.indices();
+        let idx = indices.syms;
+        indices.syms += 1;
+        indices.sym_table.insert(name.to_string(), idx);
+        self.push_msg(LunarIR::StoreSym(idx, name.to_string()));
+        idx
+    }
+
+    pub fn new_label(&mut self) -> usize {
+        let label = self.labels;
+        self.labels += 1;
+        label
+    }
+
+    pub fn declare_local(&mut self, name: &str) -> usize {
+        let indices = self.indices();
+        let idx = indices.locals;
+        indices.locals += 1;
+        indices.scope.push((name.to_string(), idx));
+        idx
+    }
+
+    // finds the local slot of the name and how many ireps up it lives,
+    // 0 being the current one
+    pub fn resolve_name(&self, name: &str) -> Option<(usize, usize)> {
+        let mut irep = self.current_irep;
+        let mut up = 0;
+        loop {
+            let indices = &self.idx_of_ireps[&irep];
+            let found = indices.scope.iter().rev().find(|(local, _)| local == name);
+            if let Some((_, idx)) = found {
+                return Some((*idx, up));
+            }
+            irep = indices.parent?;
+            up += 1;
+        }
+    }
+
+    // the indices of the irep `up` levels above the current one
+    fn indices_up(&mut self, up: usize) -> &mut IrepIndices {
+        let mut irep = self.current_irep;
+        for _ in 0..up {
+            irep = self.idx_of_ireps[&irep].parent.unwrap();
+        }
+        self.idx_of_ireps.get_mut(&irep).unwrap()
+    }
+
+    // a nested function reads the local from the frame, so the register stays
+    fn capture_local(&mut self, idx: usize, up: usize) {
+        self.indices_up(up).captured.insert(idx);
+    }
+
+    // declares the local and binds it to the value on the top of the stack.
+    // Closures share the registers of their frame, so a local of a loop body
+    // used by a closure is boxed into an Array made on each iteration, and
+    // each closure gets the box of its own iteration (see walk_function)
+    fn define_local(&mut self, name: &str) -> usize {
+        let idx = self.declare_local(name);
+        self.push_msg(LunarIR::Local(idx));
+        if self.indices().loop_captures.iter().any(|names| names.contains(name)) {
+            self.indices().boxed.insert(idx);
+            self.push_msg(LunarIR::GetLocal(idx));
+            self.push_msg(LunarIR::Array(1));
+            self.push_msg(LunarIR::SetLocal(idx));
+        }
+        idx
+    }
+
+    // leaves the locals declared since the scope started, giving their
+    // registers back unless one of them was captured
+    fn close_scope(&mut self, scope_len: usize) {
+        let indices = self.indices();
+        if let Some(&(_, first)) = indices.scope.get(scope_len) {
+            if !(first..indices.locals).any(|idx| indices.captured.contains(&idx)) {
+                self.push_msg(LunarIR::FreeLocals(first));
+            }
+        }
+        self.indices().scope.truncate(scope_len);
+    }
+
+    // `break` jumps to end_label, and the locals of the loop with one of
+    // the names are boxed by define_local
+    fn enter_loop(&mut self, end_label: usize, captures: HashSet<String>) {
+        self.indices().loop_exits.push(end_label);
+        self.indices().loop_captures.push(captures);
+    }
+
+    fn leave_loop(&mut self) {
+        self.indices().loop_exits.pop();
+        self.indices().loop_captures.pop();
+    }
+
+    pub fn walk(&mut self, root: &Block) -> Result<(), CompileError> {
         self.walk_block(root);
+        match self.error.take() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
     }
 
     pub fn walk_block(&mut self, block: &Block) {
@@ -49,26 +250,30 @@ impl Walker {
         self.walk_chunk(chunk);
     }
 
-    pub fn walk_chunk(&mut self, chunk: &Chunk) {
+    // starts a child irep and returns the irep to go back to
+    fn enter_irep(&mut self) -> usize {
         self.push_msg(LunarIR::ChunkStart(self.idx_of_irep));
         let before_irep = self.current_irep;
         self.current_irep = self.idx_of_irep;
         self.idx_of_irep += 1;
-        self.idx_of_ireps.insert(
-            self.idx_of_irep,
-            IrepIndices {
-                locals: 0,
-                syms: 0,
-                pool: 0,
-            },
-        );
-        if self.current_irep != 0 {
-            // TODO: replace dummy operand of OP_ENTER
-            self.push_msg(LunarIR::Enter(0x40000));
-            // dummy locals = 1
-            self.idx_of_ireps.get_mut(&self.current_irep).unwrap().locals += 1;
+        // the root irep has no parent
+        let parent = (self.current_irep != before_irep).then_some(before_irep);
+        self.idx_of_ireps.insert(self.current_irep, IrepIndices { parent, ..Default::default() });
+        before_irep
+    }
+
+    fn leave_irep(&mut self, before_irep: usize) {
+        if self.current_irep == 0 {
+            self.push_msg(LunarIR::Stop);
+        } else {
+            self.current_irep = before_irep;
         }
+        self.push_msg(LunarIR::ChunkEnd);
+    }
 
+    fn walk_chunk_body(&mut self, chunk: &Chunk) {
+        let scope_len = self.indices().scope.len();
+        self.enter_label_block(chunk, true);
         let statements = &chunk.0;
         for statement in statements {
             self.walk_stat(statement);
@@ -79,98 +284,1687 @@ impl Walker {
         } else {
             self.push_msg(LunarIR::NoReturn);
         }
+        self.leave_label_block(scope_len);
+    }
 
-        if self.current_irep == 0 {
-            self.push_msg(LunarIR::Stop);
-        } else {
-            self.current_irep = before_irep;
+    // declares the labels of a block up front so gotos can jump forward;
+    // before `until` the locals stay in scope, so no label ends the block
+    fn enter_label_block(&mut self, chunk: &Chunk, ends_block: bool) {
+        let scope_len = self.indices().scope.len();
+        let mut labels: HashMap<String, GotoLabel> = HashMap::new();
+        let statements = &chunk.0;
+        for (i, statement) in statements.iter().enumerate() {
+            let Stat::Label(name) = statement else {
+                continue;
+            };
+            if self.options.version == LuaVersion::Lua51 {
+                self.error(format!("labels are not available in Lua 5.1: '{}'", name.lexeme), name.line);
+            }
+            let defined = labels.get(&name.lexeme).or_else(|| self.find_label(&name.lexeme));
+            if let Some(defined) = defined {
+                let message = format!("label '{}' already defined on line {}", name.lexeme, defined.line);
+                self.error(message, name.line);
+            }
+            let at_end = ends_block
+                && chunk.1.is_none()
+                && statements[i + 1..].iter().all(|stat| matches!(stat, Stat::Label(_)));
+            let label = self.new_label();
+            labels.insert(
+                name.lexeme.clone(),
+                GotoLabel { label, line: name.line, at_end, reached: false },
+            );
         }
+        self.indices().label_blocks.push(LabelBlock { scope_len, labels });
+    }
 
-        self.push_msg(LunarIR::ChunkEnd);
+    // gotos still waiting for a label only see the locals outside the block
+    fn leave_label_block(&mut self, scope_len: usize) {
+        let indices = self.indices();
+        indices.label_blocks.pop();
+        for pending in indices.pending_gotos.iter_mut() {
+            pending.active = pending.active.min(scope_len);
+        }
+    }
+
+    fn find_label(&self, name: &str) -> Option<&GotoLabel> {
+        let indices = &self.idx_of_ireps[&self.current_irep];
+        indices.label_blocks.iter().rev().find_map(|block| block.labels.get(name))
+    }
+
+    pub fn walk_chunk(&mut self, chunk: &Chunk) {
+        let before_irep = self.enter_irep();
+        if self.current_irep == 0 && self.options.globals == GlobalsMode::GlobalTable {
+            let sym = self.intern_sym("$_G");
+            self.push_msg(LunarIR::InitGlobals(sym));
+        }
+        // the main chunk is a vararg function called without arguments
+        self.push_msg(LunarIR::Array(0));
+        let vararg = self.declare_local("...");
+        self.push_msg(LunarIR::Local(vararg));
+        self.walk_chunk_body(chunk);
+        self.leave_irep(before_irep);
+    }
+
+    // compiles the body into a child irep and pushes a lambda of it
+    pub fn walk_function(&mut self, body: &FuncBody) {
+        let boxes: Vec<(String, usize)> = {
+            let indices = self.indices();
+            indices.scope.iter().filter(|(_, idx)| indices.boxed.contains(idx)).cloned().collect()
+        };
+        if boxes.is_empty() {
+            self.walk_lambda(body);
+            return;
+        }
+
+        // the boxes of this iteration are copied into the frame of a lambda
+        // called right away, whose registers the closure then captures
+        let rep = self.indices().reps;
+        self.indices().reps += 1;
+        let before_irep = self.enter_irep();
+        self.push_msg(LunarIR::Enter(0));
+        for (name, idx) in boxes {
+            self.push_msg(LunarIR::GetUpvar(idx, 0));
+            let local = self.declare_local(&name);
+            self.push_msg(LunarIR::Local(local));
+            self.indices().boxed.insert(local);
+        }
+        self.walk_lambda(body);
+        self.push_msg(LunarIR::Return);
+        self.leave_irep(before_irep);
+
+        self.push_msg(LunarIR::Lambda(rep));
+        let call = self.intern_sym("call");
+        self.push_msg(LunarIR::Send(call, 0));
+    }
+
+    fn walk_lambda(&mut self, body: &FuncBody) {
+        let FuncBody(ParamList(names, vararg), block) = body;
+        let params = &names.0;
+        // ENTER takes at most 31 optional arguments; the others are
+        // taken from the rest argument
+        let (params, extra_params) = params.split_at(params.len().min(0x1f));
+
+        let rep = self.indices().reps;
+        self.indices().reps += 1;
+        let before_irep = self.enter_irep();
+
+        // every parameter is optional and extra arguments go to the rest
+        // argument, so a call never fails on arity as in Lua
+        let aspec = ((params.len() as u32) << 13) | (1 << 12);
+        self.push_msg(LunarIR::Enter(aspec));
+        for name in params.iter() {
+            self.declare_local(&name.lexeme);
+        }
+        // `...` is not a name, so it can only be found by vararg_local
+        let rest = self.declare_local(if *vararg { "..." } else { "(vararg)" });
+
+        // ENTER skips one JMP per optional argument given, so parameters
+        // left out are set to nil rather than whatever the caller left there
+        if !params.is_empty() {
+            let init_labels: Vec<usize> = params.iter().map(|_| self.new_label()).collect();
+            let body_label = self.new_label();
+            for label in init_labels.iter() {
+                self.push_msg(LunarIR::Jump(*label));
+            }
+            self.push_msg(LunarIR::Jump(body_label));
+            for (idx, label) in init_labels.iter().enumerate() {
+                self.push_msg(LunarIR::Label(*label));
+                self.push_msg(LunarIR::Value(LunarValue::Nil));
+                self.push_msg(LunarIR::SetLocal(idx));
+            }
+            self.push_msg(LunarIR::Label(body_label));
+        }
+        for name in extra_params.iter() {
+            let shift = self.intern_sym("shift");
+            self.push_msg(LunarIR::GetLocal(rest));
+            self.push_msg(LunarIR::Send(shift, 0));
+            let idx = self.declare_local(&name.lexeme);
+            self.push_msg(LunarIR::Local(idx));
+        }
+
+        self.walk_chunk_body(&block.0);
+        self.leave_irep(before_irep);
+
+        self.push_msg(LunarIR::Lambda(rep));
+    }
+
+    // walks a block inlined into the current irep, e.g. a branch of `if`
+    pub fn walk_scoped_block(&mut self, block: &Block) {
+        let chunk = &block.0;
+        let scope_len = self.indices().scope.len();
+        self.enter_label_block(chunk, true);
+
+        for statement in chunk.0.iter() {
+            self.walk_stat(statement);
+        }
+        if let Some(last_stat) = &chunk.1 {
+            self.walk_laststat(last_stat);
+        }
+
+        self.leave_label_block(scope_len);
+        self.close_scope(scope_len);
     }
 
     pub fn walk_stat(&mut self, stat: &Stat) {
         match stat {
             Stat::FunctionCall(function_call) => {
-                let func_name = &function_call.0;
-                let args = &function_call.2;
+                self.walk_function_call(function_call);
+                // discard the result
+                self.push_msg(LunarIR::Pop);
+            },
+            Stat::Assign(vars, exprs) => {
+                let vars = &vars.0;
 
-                // walk_var
+                // tables and keys of the targets are evaluated first, then
+                // all values before any assignment
+                let mut targets = Vec::new();
+                for var in vars.iter() {
+                    let slot = targets.iter().flatten().count() * 2;
+                    let indexed = self.walk_assign_target(var);
+                    targets.push(indexed.then_some(slot));
+                }
+                let pushed = targets.iter().flatten().count() * 2;
+                self.walk_exprs_adjusted(&exprs.0, vars.len());
+
+                if let [Some(_)] = targets.as_slice() {
+                    let delete = self.intern_sym("delete");
+                    self.push_msg(LunarIR::SetIndex(delete));
+                    return;
+                }
+
+                for (i, var) in vars.iter().enumerate().rev() {
+                    match targets[i] {
+                        Some(slot) => {
+                            let delete = self.intern_sym("delete");
+                            let depth = pushed + i - slot;
+                            self.push_msg(LunarIR::Copy(depth));
+                            self.push_msg(LunarIR::Copy(depth));
+                            self.push_msg(LunarIR::Copy(2));
+                            self.push_msg(LunarIR::SetIndex(delete));
+                            self.push_msg(LunarIR::Pop);
+                        },
+                        None => self.walk_assign_var(var),
+                    }
+                }
+                for _ in 0..pushed {
+                    self.push_msg(LunarIR::Pop);
+                }
+            },
+            Stat::LocalDeclVar(names, exprs) => {
+                let names = &names.0;
+                let exprs = match exprs {
+                    Some(exprs) => exprs.0.as_slice(),
+                    None => &[],
+                };
+
+                self.walk_exprs_adjusted(exprs, names.len());
+
+                // declared after the values, so `local x = x` reads the outer x
+                for name in names.iter() {
+                    self.define_local(&name.lexeme);
+                }
+            },
+            Stat::If(cond, block, elseifs, else_block) => {
+                let end_label = self.new_label();
+                let mut branches = vec![(cond, block)];
+                branches.extend(elseifs.iter().map(|(cond, block)| (cond, block)));
+
+                for (i, (cond, block)) in branches.iter().enumerate() {
+                    let next_label = self.new_label();
+                    self.walk_expr(cond);
+                    self.push_msg(LunarIR::JumpNot(next_label));
+                    self.push_msg(LunarIR::Pop);
+                    self.walk_scoped_block(block);
+                    if i + 1 < branches.len() || else_block.is_some() {
+                        self.push_msg(LunarIR::Jump(end_label));
+                    }
+                    self.push_msg(LunarIR::Label(next_label));
+                }
+                if let Some(block) = else_block {
+                    self.walk_scoped_block(block);
+                }
+                self.push_msg(LunarIR::Label(end_label));
+            },
+            Stat::Do(block) => {
+                self.walk_scoped_block(block);
+            },
+            Stat::While(cond, block) => {
+                let start_label = self.new_label();
+                let end_label = self.new_label();
+
+                self.push_msg(LunarIR::Label(start_label));
+                self.walk_expr(cond);
+                self.push_msg(LunarIR::JumpNot(end_label));
+                self.push_msg(LunarIR::Pop);
+
+                self.enter_loop(end_label, closure_names(block));
+                self.walk_scoped_block(block);
+                self.leave_loop();
+
+                self.push_msg(LunarIR::Jump(start_label));
+                self.push_msg(LunarIR::Label(end_label));
+            },
+            Stat::Repeat(cond, block) => {
+                let start_label = self.new_label();
+                let end_label = self.new_label();
+                let chunk = &block.0;
+                let scope_len = self.indices().scope.len();
+
+                self.push_msg(LunarIR::Label(start_label));
+                let mut captures = closure_names(block);
+                collect_names(Node::Expr(cond), false, &mut captures);
+                self.enter_loop(end_label, captures);
+                self.enter_label_block(chunk, false);
+                for statement in chunk.0.iter() {
+                    self.walk_stat(statement);
+                }
+                if let Some(last_stat) = &chunk.1 {
+                    self.walk_laststat(last_stat);
+                }
+
+                // the condition can still see the locals of the body
+                self.walk_expr(cond);
+                self.leave_loop();
+                self.push_msg(LunarIR::JumpNot(start_label));
+                self.push_msg(LunarIR::Pop);
+                self.leave_label_block(scope_len);
+                self.close_scope(scope_len);
+
+                self.push_msg(LunarIR::Label(end_label));
+            },
+            Stat::ForIn(names, exprs, block) => {
+                self.walk_generic_for(names, exprs, block);
+            },
+            Stat::For(token, expr, expr1, expr2, block) => {
+                self.walk_numeric_for(token, expr, expr1, expr2.as_deref(), block);
+            },
+            Stat::Function(FuncName(names, method), body) => {
+                // `function T:m()` is `function T.m(self)`
+                let mut names = names.clone();
+                let body = match method {
+                    Some(method) => {
+                        names.push(method.clone());
+                        let FuncBody(ParamList(params, vararg), block) = body;
+                        let mut params = params.clone();
+                        params.0.insert(0, Token::new(TokenType::Name, "self", method.line));
+                        &FuncBody(ParamList(params, *vararg), block.clone())
+                    },
+                    None => body,
+                };
+                match names.as_slice() {
+                    [name] => {
+                        self.walk_function(body);
+                        self.walk_assign_var(&Var::VarName(name.clone()));
+                    },
+                    [first, fields @ .., last] => {
+                        // `function a.b.c()` is `a.b.c = function()`
+                        let mut prefix = PrefixExp::PrefixVar(Box::new(Var::VarName(first.clone())));
+                        for field in fields {
+                            prefix = PrefixExp::PrefixVar(Box::new(Var::VarMember(prefix, field.clone())));
+                        }
+                        self.walk_index_target(&Var::VarMember(prefix, last.clone()));
+                        self.walk_function(body);
+                        let delete = self.intern_sym("delete");
+                        self.push_msg(LunarIR::SetIndex(delete));
+                    },
+                    [] => unreachable!(),
+                }
+            },
+            Stat::LocalFunction(name, body) => {
+                // the local is in scope inside its own body
+                self.push_msg(LunarIR::Value(LunarValue::Nil));
+                self.define_local(&name.lexeme);
+                self.walk_function(body);
+                self.walk_set_name(name);
+            },
+            Stat::Goto(name) => {
+                self.walk_goto(name);
+            },
+            Stat::Label(name) => {
+                self.walk_label(name);
+            },
+        }
+    }
+
+    // pushes exactly `wanted` values, as Lua adjusts expression lists
+    pub fn walk_exprs_adjusted(&mut self, exprs: &[Expr], wanted: usize) {
+        // extra values are still evaluated, then dropped
+        for (i, expr) in exprs.iter().enumerate() {
+            if i + 1 == exprs.len() && i < wanted && self.is_multi(expr) {
+                // the last expression fills up the rest
+                self.walk_results(expr);
+                self.push_msg(LunarIR::Unpack(wanted - i));
+                return;
+            }
+            self.walk_expr(expr);
+            if i >= wanted {
+                self.push_msg(LunarIR::Pop);
+            }
+        }
+        for _ in exprs.len()..wanted {
+            self.push_msg(LunarIR::Value(LunarValue::Nil));
+        }
+    }
+
+    pub fn walk_function_call(&mut self, function_call: &FunctionCall) {
+        let func_name = &function_call.0;
+        let args = &function_call.2;
+
+        if let Some(method) = &function_call.1 {
+            self.walk_method_call(func_name, method, args);
+            return;
+        }
+        if self.walk_call_intrinsic(function_call) {
+            return;
+        }
+
+        let call = self.intern_sym("call");
+        match func_name.as_ref() {
+            PrefixExp::PrefixVar(var) if matches!(var.as_ref(), Var::VarName(_)) => {
+                let Var::VarName(name) = var.as_ref() else { unreachable!() };
+                if self.resolve_name(&name.lexeme).is_some() {
+                    self.walk_get_name(name);
+                    self.push_msg(LunarIR::MethodCallStart(call));
+                } else {
+                    // a global not set from Lua falls back to a method
+                    // of self, e.g. Kernel#print
+                    self.walk_get_global(&name.lexeme);
+                    let sym = self.intern_sym(&name.lexeme);
+                    self.push_msg(LunarIR::GlobalCallStart(sym, call));
+                }
+            },
+            _ => {
                 self.walk_prefixexpr(func_name);
+                self.push_msg(LunarIR::MethodCallStart(call));
+            },
+        }
+
+        self.walk_args(args);
 
-                self.push_msg(LunarIR::FunctionCallStart(
-                    self.idx_of_ireps[&self.current_irep].syms - 1,
-                ));
+        self.push_msg(LunarIR::FunctionCallEnd);
+    }
 
-                self.walk_args(args);
+    // `obj:name(args)` calls the function stored at `name` of a table with
+    // obj as self, and sends `name` to any other object
+    fn walk_method_call(&mut self, receiver: &PrefixExp, method: &Token, args: &Args) {
+        let hash = self.intern_sym("Hash");
+        let case_eq = self.intern_sym("===");
+        let sym = self.intern_sym(&method.lexeme);
+        let call = self.intern_sym("call");
+        let label = self.new_label();
 
-                self.push_msg(LunarIR::FunctionCallEnd);
+        self.walk_prefixexpr(receiver);
+        self.push_msg(LunarIR::GetConst(hash));
+        self.push_msg(LunarIR::Copy(1));
+        self.push_msg(LunarIR::Send(case_eq, 1));
+        self.push_msg(LunarIR::JumpNot(label));
+        self.push_msg(LunarIR::Pop);
+        self.push_msg(LunarIR::Copy(0));
+        self.walk_string(&method.lexeme);
+        self.push_msg(LunarIR::GetIndex);
+        self.push_msg(LunarIR::Label(label));
+        self.push_msg(LunarIR::Copy(1));
+        self.push_msg(LunarIR::SelfCallStart(sym, call));
+
+        self.walk_args(args);
+
+        self.push_msg(LunarIR::FunctionCallEnd);
+    }
+
+    // `select`, `table.pack` and `math.type` work on the arguments without
+    // a call
+    fn walk_call_intrinsic(&mut self, function_call: &FunctionCall) -> bool {
+        let FunctionCall(func, _, args) = function_call;
+        let args = match args {
+            Args::ArgsList(args) => args.0.as_slice(),
+            _ => return false,
+        };
+        match (self.global_path(func).as_deref(), args) {
+            (Some("select"), [Expr::String(n), values @ ..]) if n == b"#" => {
+                self.walk_array(values);
+                let sym = self.intern_sym("size");
+                self.push_msg(LunarIR::Send(sym, 0));
+            },
+            (Some("select"), [n, values @ ..]) => {
+                // pushes the values from the nth on as multiple values
+                self.walk_array(values);
+                match self.literal_integer(n) {
+                    Some(n) if n < 0 => {
+                        self.walk_integer(-n);
+                        let sym = self.intern_sym("last");
+                        self.push_msg(LunarIR::Send(sym, 1));
+                    },
+                    Some(_) => {
+                        self.walk_expr(n);
+                        self.push_msg(LunarIR::SubI(1));
+                        let sym = self.intern_sym("drop");
+                        self.push_msg(LunarIR::Send(sym, 1));
+                    },
+                    None => {
+                        // a negative index counts from the end
+                        let last = self.intern_sym("last");
+                        let neg = self.intern_sym("-@");
+                        let drop = self.intern_sym("drop");
+                        let positive_label = self.new_label();
+                        let end_label = self.new_label();
+                        self.walk_expr(n);
+                        self.push_msg(LunarIR::Copy(0));
+                        self.push_msg(LunarIR::Value(LunarValue::Integer(0)));
+                        self.push_msg(LunarIR::BinOp(BinOp::Lt));
+                        self.push_msg(LunarIR::JumpNot(positive_label));
+                        self.push_msg(LunarIR::Pop);
+                        self.push_msg(LunarIR::Send(neg, 0));
+                        self.push_msg(LunarIR::Send(last, 1));
+                        self.push_msg(LunarIR::Jump(end_label));
+                        // the stack at positive_label still holds the values,
+                        // the index and the test
+                        self.push_msg(LunarIR::Value(LunarValue::Nil));
+                        self.push_msg(LunarIR::Value(LunarValue::Nil));
+                        self.push_msg(LunarIR::Label(positive_label));
+                        self.push_msg(LunarIR::Pop);
+                        self.push_msg(LunarIR::SubI(1));
+                        self.push_msg(LunarIR::Send(drop, 1));
+                        self.push_msg(LunarIR::Label(end_label));
+                    },
+                }
+            },
+            (Some("table.pack"), values) => self.walk_table_pack(values),
+            (Some("math.type"), [value]) if self.options.version != LuaVersion::Lua51 => {
+                self.walk_expr(value);
+                self.walk_math_type();
+            },
+            _ => return false,
+        }
+        true
+    }
+
+    // "integer" for an Integer, "float" for a Float and nil for the others
+    fn walk_math_type(&mut self) {
+        let case_eq = self.intern_sym("===");
+        let end_label = self.new_label();
+        for (class, name) in [("Integer", "integer"), ("Float", "float")] {
+            let next_label = self.new_label();
+            let class = self.intern_sym(class);
+            self.push_msg(LunarIR::GetConst(class));
+            self.push_msg(LunarIR::Copy(1));
+            self.push_msg(LunarIR::Send(case_eq, 1));
+            self.push_msg(LunarIR::JumpNot(next_label));
+            self.push_msg(LunarIR::Pop);
+            self.walk_string(name);
+            self.push_msg(LunarIR::Store(0));
+            self.push_msg(LunarIR::Jump(end_label));
+            self.push_msg(LunarIR::Label(next_label));
+        }
+        self.push_msg(LunarIR::Value(LunarValue::Nil));
+        self.push_msg(LunarIR::Store(0));
+        self.push_msg(LunarIR::Label(end_label));
+    }
+
+    // t = {}; for i = 0, #values - 1 do t[i + 1] = values[i] end; t.n = #values
+    fn walk_table_pack(&mut self, values: &[Expr]) {
+        let size = self.intern_sym("size");
+        let delete = self.intern_sym("delete");
+
+        self.push_msg(LunarIR::NewTable(0));
+        self.walk_array(values);
+        self.push_msg(LunarIR::Copy(1));
+        self.walk_string("n");
+        self.push_msg(LunarIR::Copy(2));
+        self.push_msg(LunarIR::Send(size, 0));
+        self.push_msg(LunarIR::SetIndex(delete));
+        self.walk_table_append(1);
+    }
+
+    // pops an Array below the table on the top and stores its values at
+    // the keys from `first`
+    fn walk_table_append(&mut self, first: usize) {
+        let size = self.intern_sym("size");
+        let delete = self.intern_sym("delete");
+        let loop_label = self.new_label();
+        let end_label = self.new_label();
+
+        self.push_msg(LunarIR::Value(LunarValue::Integer(0)));
+
+        self.push_msg(LunarIR::Label(loop_label));
+        self.push_msg(LunarIR::Copy(0));
+        self.push_msg(LunarIR::Copy(2));
+        self.push_msg(LunarIR::Send(size, 0));
+        self.push_msg(LunarIR::BinOp(BinOp::Lt));
+        self.push_msg(LunarIR::JumpNot(end_label));
+        self.push_msg(LunarIR::Pop);
+
+        self.push_msg(LunarIR::Copy(2));
+        self.push_msg(LunarIR::Copy(1));
+        match u8::try_from(first) {
+            Ok(first) => self.push_msg(LunarIR::AddI(first)),
+            Err(_) => {
+                self.walk_integer(first as i64);
+                self.push_msg(LunarIR::BinOp(BinOp::Add));
+            },
+        }
+        self.push_msg(LunarIR::Copy(3));
+        self.push_msg(LunarIR::Copy(3));
+        self.push_msg(LunarIR::GetIndex);
+        self.push_msg(LunarIR::SetIndex(delete));
+        self.push_msg(LunarIR::AddI(1));
+        self.push_msg(LunarIR::Jump(loop_label));
+        self.push_msg(LunarIR::Label(end_label));
+        self.push_msg(LunarIR::Pop);
+        self.push_msg(LunarIR::Pop);
+    }
+
+    // `...` and function calls have any number of values
+    pub fn is_multi(&self, expr: &Expr) -> bool {
+        matches!(expr, Expr::Dots(_) | Expr::PrefixExp(PrefixExp::PrefixCall(_)))
+    }
+
+    // pushes the results of a multi-valued expression as they are: a
+    // single value, or an Array of multiple values (see walk_laststat)
+    pub fn walk_results(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Dots(dots) => match self.vararg_local() {
+                Some(idx) => self.push_msg(LunarIR::GetLocal(idx)),
+                None => {
+                    self.error("cannot use '...' outside a vararg function", dots.line);
+                    self.push_msg(LunarIR::Array(0));
+                },
             },
-            Stat::For(_token, expr, expr1, expr2, block) => {
-                let begin = self.ensure_expr_as_number(expr) as usize;
-                let end = self.ensure_expr_as_number(expr1) as usize;
-                let step = match expr2 {
-                    Some(expr) => self.ensure_expr_as_number(expr),
-                    None => 1.0,
-                } as usize;
-
-                self.push_msg(LunarIR::StoreSym(
-                    self.idx_of_ireps[&self.current_irep].syms,
-                    "each".to_string(),
-                ));
-                self.push_msg(LunarIR::ForStart(self.idx_of_ireps[&self.current_irep].syms));
-                self.idx_of_ireps.get_mut(&self.current_irep).unwrap().syms += 1;
-
-                self.push_msg(LunarIR::ForParam(
-                    begin,
-                    end,
-                    step,
-                ));
-                // TODO: token is_a varname of each,
-                // which can be used inside of this block!
-                // TODO: real handle of children reps... :(
-                self.push_msg(LunarIR::Block(self.idx_of_irep - 1));
-                self.walk_block(block);
-                self.push_msg(LunarIR::ForEnd);
+            Expr::PrefixExp(PrefixExp::PrefixCall(function_call)) => {
+                self.walk_function_call(function_call);
+            },
+            _ => panic!("Not a multi-valued expression: {:?}", expr),
+        }
+    }
+
+    // pushes all the values of a multi-valued expression as one Array
+    pub fn walk_multi(&mut self, expr: &Expr) {
+        self.walk_results(expr);
+        if matches!(expr, Expr::Dots(_)) {
+            return;
+        }
+
+        // a single result is wrapped: Array === v ? v : [v]
+        let done_label = self.new_label();
+        let array = self.intern_sym("Array");
+        let eqq = self.intern_sym("===");
+        self.push_msg(LunarIR::GetConst(array));
+        self.push_msg(LunarIR::Copy(1));
+        self.push_msg(LunarIR::Send(eqq, 1));
+        self.push_msg(LunarIR::JumpIf(done_label));
+        self.push_msg(LunarIR::Pop);
+        self.push_msg(LunarIR::Array(1));
+        self.push_msg(LunarIR::Label(done_label));
+    }
+
+    // pushes the values as one Array, expanding the last one
+    pub fn walk_array(&mut self, exprs: &[Expr]) {
+        match exprs.split_last() {
+            Some((last, init)) if self.is_multi(last) => {
+                for expr in init.iter() {
+                    self.walk_expr(expr);
+                }
+                self.walk_multi(last);
+                self.push_msg(LunarIR::Pack(init.len()));
             },
             _ => {
-                // Handle other types of statements
-                panic!("Unsupported statement: {:?}", stat);
-            }
+                for expr in exprs.iter() {
+                    self.walk_expr(expr);
+                }
+                self.push_msg(LunarIR::Array(exprs.len()));
+            },
         }
     }
 
     pub fn walk_args(&mut self, args: &Args) {
         match args {
             Args::ArgsString(string) => {
-                self.push_msg(LunarIR::PoolString(
-                    self.idx_of_ireps[&self.current_irep].pool,
-                    string.to_owned(),
-                ));
-                self.push_msg(LunarIR::FunctionCallArg(
-                    0,
-                    LunarValue::String(self.idx_of_ireps[&self.current_irep].pool),
-                ));
-                self.idx_of_ireps.get_mut(&self.current_irep).unwrap().pool += 1;
+                self.walk_bytes(string);
             },
-            _ => {
-                // Handle other types of arguments
-                panic!("Unsupported argument: {:?}", args);
+            Args::ArgsList(exprs) => {
+                let exprs = &exprs.0;
+                let expands = exprs.last().is_some_and(|last| self.is_multi(last));
+                match exprs.as_slice() {
+                    // e.g. f(g(x)): the results are passed on without a copy
+                    [expr] if expands => {
+                        self.walk_multi(expr);
+                        self.push_msg(LunarIR::SplatArgs);
+                    },
+                    // argc of 15 and more means the arguments are in an Array
+                    _ if expands || exprs.len() >= 15 => {
+                        self.walk_array(exprs);
+                        self.push_msg(LunarIR::SplatArgs);
+                    },
+                    _ => {
+                        for expr in exprs.iter() {
+                            self.walk_expr(expr);
+                        }
+                    },
+                }
+            },
+            // f{...} passes the table as the only argument
+            Args::ArgsTable(table) => {
+                self.walk_table(table);
+            },
+        }
+    }
+
+    // tables are Hashes: positional fields are stored at the keys 1..n
+    // and named fields at String keys
+    pub fn walk_table(&mut self, table: &TableConstructor) {
+        let fields = &table.0.0;
+        let mut position = 0;
+
+        // leading fields which can't be nil are created with the table, and
+        // added FIELDS_PER_FLUSH at a time so their registers stay few
+        let constants = fields.iter().take_while(|field| is_constant_field(field)).count();
+        let mut batches = fields[..constants].chunks(FIELDS_PER_FLUSH);
+        let first = batches.next().unwrap_or_default();
+        for field in first {
+            self.walk_field(field, &mut position);
+        }
+        self.push_msg(LunarIR::NewTable(first.len()));
+        for batch in batches {
+            for field in batch {
+                self.walk_field(field, &mut position);
+            }
+            self.push_msg(LunarIR::TableAdd(batch.len()));
+        }
+
+        for (i, field) in fields.iter().enumerate().skip(constants) {
+            match field {
+                Field::UniExp(expr) if i + 1 == fields.len() && self.is_multi(expr) => {
+                    self.walk_multi(expr);
+                    self.walk_table_append(position + 1);
+                },
+                _ => {
+                    let delete = self.intern_sym("delete");
+                    self.push_msg(LunarIR::Copy(0));
+                    self.walk_field(field, &mut position);
+                    self.push_msg(LunarIR::SetIndex(delete));
+                },
             }
         }
     }
 
-    // TODO: replace TryInto?
-    pub fn ensure_expr_as_number(&mut self, expr: &Expr) -> f64 {
+    // pushes the key and the value of a field
+    fn walk_field(&mut self, field: &Field, position: &mut usize) {
+        match field {
+            Field::UniExp(value) => {
+                *position += 1;
+                self.walk_integer(*position as i64);
+                self.walk_expr(value);
+            },
+            Field::AssignName(name, value) => {
+                self.walk_string(&name.lexeme);
+                self.walk_expr(value);
+            },
+            Field::AssignIdx(key, value) => {
+                self.walk_set_key(key);
+                self.walk_expr(value);
+            },
+        }
+    }
+
+    // integers in the range of LOADI32 are loaded as immediates and other
+    // integers from the pool
+    pub fn walk_integer(&mut self, number: i64) {
+        if i32::try_from(number).is_ok() {
+            self.push_msg(LunarIR::Value(LunarValue::Integer(number)));
+            return;
+        }
+        let idx = match self.indices().integer_table.get(&number) {
+            Some(idx) => *idx,
+            None => {
+                let indices = self.indices();
+                let idx = indices.pool;
+                indices.pool += 1;
+                indices.integer_table.insert(number, idx);
+                self.push_msg(LunarIR::PoolInteger(idx, number));
+                idx
+            }
+        };
+        self.push_msg(LunarIR::Value(LunarValue::Literal(idx)));
+    }
+
+    pub fn walk_float(&mut self, number: f64) {
+        if self.options.version == LuaVersion::Lua51
+            && number.fract() == 0.0
+            && (i64::MIN as f64..i64::MAX as f64).contains(&number)
+        {
+            self.walk_integer(number as i64);
+            return;
+        }
+        let idx = match self.indices().float_table.get(&number.to_bits()) {
+            Some(idx) => *idx,
+            None => {
+                let indices = self.indices();
+                let idx = indices.pool;
+                indices.pool += 1;
+                indices.float_table.insert(number.to_bits(), idx);
+                self.push_msg(LunarIR::PoolFloat(idx, number));
+                idx
+            }
+        };
+        self.push_msg(LunarIR::Value(LunarValue::Literal(idx)));
+    }
+
+    pub fn walk_string(&mut self, string: &str) {
+        self.walk_bytes(string.as_bytes());
+    }
+
+    // the pool holds strings up to 64KiB, so longer ones are joined with
+    // STRCAT onto the fresh copy of their first piece
+    pub fn walk_bytes(&mut self, bytes: &[u8]) {
+        if bytes.len() > POOL_STRING_MAX {
+            for (i, piece) in bytes.chunks(POOL_STRING_MAX).enumerate() {
+                self.walk_bytes(piece);
+                if i > 0 {
+                    self.push_msg(LunarIR::BinOp(BinOp::Concat));
+                }
+            }
+            return;
+        }
+        let idx = match self.indices().pool_table.get(bytes) {
+            Some(idx) => *idx,
+            None => {
+                let indices = self.indices();
+                let idx = indices.pool;
+                indices.pool += 1;
+                indices.pool_table.insert(bytes.to_vec(), idx);
+                self.push_msg(LunarIR::PoolString(idx, bytes.to_vec()));
+                idx
+            }
+        };
+        self.push_msg(LunarIR::Value(LunarValue::String(idx)));
+    }
+
+    pub fn walk_expr(&mut self, expr: &Expr) {
         match expr {
-            Expr::Number(f) => *f,
+            Expr::Nil => self.push_msg(LunarIR::Value(LunarValue::Nil)),
+            Expr::True => self.push_msg(LunarIR::Value(LunarValue::Boolean(true))),
+            Expr::False => self.push_msg(LunarIR::Value(LunarValue::Boolean(false))),
+            Expr::Integer(n) => self.walk_integer(*n),
+            Expr::Float(f) => self.walk_float(*f),
+            Expr::String(string) => self.walk_bytes(string),
+            Expr::Dots(_) => {
+                // only the first value is used
+                self.walk_results(expr);
+                self.push_msg(LunarIR::Unpack(1));
+            },
+            Expr::PrefixExp(prefix_expr) => self.walk_prefixexpr(prefix_expr),
+            Expr::ExprBinop(lhs, op, rhs) => self.walk_binop(lhs, op, rhs),
+            Expr::Unop(op, expr) => self.walk_unop(op, expr),
+            Expr::Function(Function(body)) => self.walk_function(body),
+            Expr::TableConstructor(table) => self.walk_table(table),
+        }
+    }
+
+    pub fn walk_binop(&mut self, lhs: &Expr, op: &Binop, rhs: &Expr) {
+        let line = op.0.line;
+        let op = op.0.token_type;
+        if op == TokenType::And || op == TokenType::Or {
+            // `a and b` / `a or b` evaluate to one of the operands;
+            // nil and false are the only falsy values in both languages
+            let label = self.new_label();
+            self.walk_expr(lhs);
+            if op == TokenType::And {
+                self.push_msg(LunarIR::JumpNot(label));
+            } else {
+                self.push_msg(LunarIR::JumpIf(label));
+            }
+            self.push_msg(LunarIR::Pop);
+            self.walk_expr(rhs);
+            self.push_msg(LunarIR::Label(label));
+            return;
+        }
+        if op == TokenType::Concat {
+            // STRCAT appends in place, so a chain is appended to a new
+            // String; numbers are converted with to_s
+            let mut operands = Vec::new();
+            concat_operands(lhs, &mut operands);
+            concat_operands(rhs, &mut operands);
+            self.walk_string("");
+            for operand in operands {
+                self.walk_concat_operand(operand);
+                self.push_msg(LunarIR::BinOp(BinOp::Concat));
+            }
+            return;
+        }
+
+        if matches!(op, TokenType::Amp | TokenType::Bar | TokenType::Tilda | TokenType::ShL | TokenType::ShR) {
+            self.walk_bitwise(lhs, op, rhs, line);
+            return;
+        }
+
+        let small_literal = match rhs {
+            Expr::Integer(n @ 0..=255) => Some(*n as u8),
+            _ => None,
+        };
+
+        match (op, small_literal) {
+            (TokenType::Plus, Some(n)) => {
+                self.walk_expr(lhs);
+                self.push_msg(LunarIR::AddI(n));
+                return;
+            },
+            (TokenType::Minus, Some(n)) => {
+                self.walk_expr(lhs);
+                self.push_msg(LunarIR::SubI(n));
+                return;
+            },
+            _ => {}
+        }
+
+        self.walk_expr(lhs);
+        if op == TokenType::Hat {
+            // Lua's power is always float
+            let sym = self.intern_sym("to_f");
+            self.push_msg(LunarIR::Send(sym, 0));
+        }
+        self.walk_expr(rhs);
+
+        match op {
+            TokenType::Plus => self.push_msg(LunarIR::BinOp(BinOp::Add)),
+            TokenType::Minus => self.push_msg(LunarIR::BinOp(BinOp::Sub)),
+            TokenType::Aster => self.push_msg(LunarIR::BinOp(BinOp::Mul)),
+            TokenType::Slash => {
+                // Lua's division is always float, while Integer#/ floors
+                let sym = self.intern_sym("to_f");
+                self.push_msg(LunarIR::Send(sym, 0));
+                self.push_msg(LunarIR::BinOp(BinOp::Div));
+            },
+            TokenType::IDiv => {
+                if self.options.version == LuaVersion::Lua51 {
+                    self.error("'//' is not available in Lua 5.1", line);
+                }
+                // Integer#/ floors already; a finite Float quotient is
+                // floored into a Float
+                let float = self.intern_sym("Float");
+                let case_eq = self.intern_sym("===");
+                let finite = self.intern_sym("finite?");
+                let floor = self.intern_sym("floor");
+                let to_f = self.intern_sym("to_f");
+                let label = self.new_label();
+                self.push_msg(LunarIR::BinOp(BinOp::Div));
+                self.push_msg(LunarIR::GetConst(float));
+                self.push_msg(LunarIR::Copy(1));
+                self.push_msg(LunarIR::Send(case_eq, 1));
+                self.push_msg(LunarIR::JumpNot(label));
+                self.push_msg(LunarIR::Pop);
+                self.push_msg(LunarIR::Copy(0));
+                self.push_msg(LunarIR::Send(finite, 0));
+                self.push_msg(LunarIR::JumpNot(label));
+                self.push_msg(LunarIR::Pop);
+                self.push_msg(LunarIR::Send(floor, 0));
+                self.push_msg(LunarIR::Send(to_f, 0));
+                self.push_msg(LunarIR::Label(label));
+            },
+            TokenType::Perc => {
+                // Ruby's modulo is floored as in Lua, for both Integer and Float
+                let sym = self.intern_sym("%");
+                self.push_msg(LunarIR::Send(sym, 1));
+            },
+            TokenType::Hat => {
+                let sym = self.intern_sym("**");
+                self.push_msg(LunarIR::Send(sym, 1));
+            },
+            TokenType::Eql => self.walk_equal(lhs, rhs),
+            TokenType::Ne => {
+                self.walk_equal(lhs, rhs);
+                let sym = self.intern_sym("!");
+                self.push_msg(LunarIR::Send(sym, 0));
+            },
+            TokenType::Less => self.push_msg(LunarIR::BinOp(BinOp::Lt)),
+            TokenType::Le => self.push_msg(LunarIR::BinOp(BinOp::Le)),
+            TokenType::Greater => self.push_msg(LunarIR::BinOp(BinOp::Gt)),
+            TokenType::Ge => self.push_msg(LunarIR::BinOp(BinOp::Ge)),
+            _ => {
+                // Handle other types of operators
+                panic!("Unsupported binary operator: {:?}", op);
+            }
+        }
+
+        // Lua 5.1 has no integer subtype, so an integral result such as
+        // `4 / 2` or `2 ^ 3` is an Integer like the literal would be
+        let arithmetic = matches!(op, TokenType::Plus | TokenType::Minus | TokenType::Aster | TokenType::Slash | TokenType::Perc | TokenType::Hat);
+        if self.options.version == LuaVersion::Lua51 && arithmetic {
+            self.walk_integral_float();
+        }
+    }
+
+    pub fn walk_unop(&mut self, op: &Unop, expr: &Expr) {
+        let line = op.0.line;
+        let op = op.0.token_type;
+        match op {
+            // negative literals are loaded as they are
+            TokenType::Minus => match expr {
+                Expr::Integer(n) => self.walk_integer(n.wrapping_neg()),
+                Expr::Float(f) => self.walk_float(-f),
+                _ => {
+                    self.walk_expr(expr);
+                    let sym = self.intern_sym("-@");
+                    self.push_msg(LunarIR::Send(sym, 0));
+                },
+            },
+            TokenType::Not => {
+                self.walk_expr(expr);
+                let sym = self.intern_sym("!");
+                self.push_msg(LunarIR::Send(sym, 0));
+            },
+            TokenType::Opus => {
+                self.walk_expr(expr);
+                self.walk_length();
+            },
+            TokenType::Tilda => {
+                if self.options.version == LuaVersion::Lua51 {
+                    self.error("bitwise operators are not available in Lua 5.1", line);
+                }
+                self.walk_to_integer(expr);
+                let sym = self.intern_sym("~");
+                self.push_msg(LunarIR::Send(sym, 0));
+            },
             _ => {
-                // Handle other types of expressions
-                panic!("Unsupported expression: {:?}", expr);
+                // Handle other types of operators
+                panic!("Unsupported unary operator: {:?}", op);
             }
         }
     }
 
+    // Hash#== compares contents, while Lua tables are equal only to
+    // themselves, so a Hash on the left is compared with equal?
+    fn walk_equal(&mut self, lhs: &Expr, rhs: &Expr) {
+        let scalar = |expr: &Expr| {
+            matches!(expr, Expr::Nil | Expr::True | Expr::False | Expr::Integer(_) | Expr::Float(_) | Expr::String(_))
+        };
+        if scalar(lhs) || scalar(rhs) {
+            self.push_msg(LunarIR::BinOp(BinOp::Eq));
+            return;
+        }
+        let hash = self.intern_sym("Hash");
+        let case_eq = self.intern_sym("===");
+        let equal = self.intern_sym("equal?");
+        let value_label = self.new_label();
+        let end_label = self.new_label();
+        self.push_msg(LunarIR::GetConst(hash));
+        self.push_msg(LunarIR::Copy(2));
+        self.push_msg(LunarIR::Send(case_eq, 1));
+        self.push_msg(LunarIR::JumpNot(value_label));
+        self.push_msg(LunarIR::Pop);
+        self.push_msg(LunarIR::Send(equal, 1));
+        self.push_msg(LunarIR::Jump(end_label));
+        // the stack at value_label still holds both operands and the test
+        self.push_msg(LunarIR::Value(LunarValue::Nil));
+        self.push_msg(LunarIR::Value(LunarValue::Nil));
+        self.push_msg(LunarIR::Label(value_label));
+        self.push_msg(LunarIR::Pop);
+        self.push_msg(LunarIR::BinOp(BinOp::Eq));
+        self.push_msg(LunarIR::Label(end_label));
+    }
+
+    // bitwise operators of Lua 5.3 on 64-bit integers
+    fn walk_bitwise(&mut self, lhs: &Expr, op: TokenType, rhs: &Expr, line: usize) {
+        if self.options.version == LuaVersion::Lua51 {
+            self.error("bitwise operators are not available in Lua 5.1", line);
+        }
+        self.walk_to_integer(lhs);
+        let sym = match op {
+            TokenType::Amp => "&",
+            TokenType::Bar => "|",
+            TokenType::Tilda => "^",
+            _ => {
+                self.walk_shift(op == TokenType::ShL, rhs);
+                return;
+            },
+        };
+        self.walk_to_integer(rhs);
+        let sym = self.intern_sym(sym);
+        self.push_msg(LunarIR::Send(sym, 1));
+    }
+
+    // pushes the value as an integer: a Float with an integral value is
+    // converted and any other Float raises an error as in Lua
+    fn walk_to_integer(&mut self, expr: &Expr) {
+        self.walk_expr(expr);
+        if matches!(expr, Expr::Integer(_)) {
+            return;
+        }
+        let float = self.intern_sym("Float");
+        let case_eq = self.intern_sym("===");
+        let floor = self.intern_sym("floor");
+        let raise = self.intern_sym("raise");
+        let ok_label = self.new_label();
+        let end_label = self.new_label();
+
+        self.push_msg(LunarIR::GetConst(float));
+        self.push_msg(LunarIR::Copy(1));
+        self.push_msg(LunarIR::Send(case_eq, 1));
+        self.push_msg(LunarIR::JumpNot(end_label));
+        self.push_msg(LunarIR::Pop);
+        self.push_msg(LunarIR::Copy(0));
+        self.push_msg(LunarIR::Send(floor, 0));
+        self.push_msg(LunarIR::Copy(1));
+        self.push_msg(LunarIR::Copy(1));
+        self.push_msg(LunarIR::BinOp(BinOp::Eq));
+        self.push_msg(LunarIR::JumpIf(ok_label));
+        self.push_msg(LunarIR::Pop);
+        self.push_msg(LunarIR::FunctionCallStart(raise));
+        self.walk_string("number has no integer representation");
+        self.push_msg(LunarIR::FunctionCallEnd);
+        self.push_msg(LunarIR::Pop);
+        self.push_msg(LunarIR::Label(ok_label));
+        self.push_msg(LunarIR::Store(0));
+        self.push_msg(LunarIR::Label(end_label));
+    }
+
+    // STRCAT would convert any value with to_s, while Lua only
+    // concatenates strings and numbers and raises an error otherwise
+    fn walk_concat_operand(&mut self, expr: &Expr) {
+        self.walk_expr(expr);
+        if matches!(expr, Expr::Integer(_) | Expr::Float(_) | Expr::String(_)) {
+            return;
+        }
+        let case_eq = self.intern_sym("===");
+        let raise = self.intern_sym("raise");
+        let ok_label = self.new_label();
+
+        for class in ["String", "Numeric"] {
+            let class = self.intern_sym(class);
+            self.push_msg(LunarIR::GetConst(class));
+            self.push_msg(LunarIR::Copy(1));
+            self.push_msg(LunarIR::Send(case_eq, 1));
+            self.push_msg(LunarIR::JumpIf(ok_label));
+            self.push_msg(LunarIR::Pop);
+        }
+        self.push_msg(LunarIR::FunctionCallStart(raise));
+        self.walk_string("attempt to concatenate a value that is not a string or number");
+        self.push_msg(LunarIR::FunctionCallEnd);
+        self.push_msg(LunarIR::Pop);
+        self.push_msg(LunarIR::Label(ok_label));
+    }
+
+    // Lua normalizes a Float key with an integral value to an Integer,
+    // so that t[2.0] and t[2] are the same key, and compares table keys
+    // by identity
+    fn walk_key(&mut self, key: &Expr) {
+        match key {
+            Expr::Float(n) if n.fract() == 0.0 && (i64::MIN as f64..i64::MAX as f64).contains(n) => {
+                self.walk_integer(*n as i64);
+                return;
+            },
+            Expr::Nil | Expr::True | Expr::False | Expr::Integer(_) | Expr::Float(_) | Expr::String(_) => {
+                self.walk_expr(key);
+                return;
+            },
+            _ => self.walk_expr(key),
+        }
+        self.walk_integral_float();
+        self.walk_table_key();
+    }
+
+    // a Hash is hashed by its contents, so a table on the top of the stack
+    // is replaced with an Object standing for it, made on first use and
+    // kept in @lua_key of the table; its @lua_table gives the table back
+    fn walk_table_key(&mut self) {
+        let hash = self.intern_sym("Hash");
+        let case_eq = self.intern_sym("===");
+        let object = self.intern_sym("Object");
+        let new = self.intern_sym("new");
+        let iv_get = self.intern_sym("instance_variable_get");
+        let iv_set = self.intern_sym("instance_variable_set");
+        let new_label = self.new_label();
+        let end_label = self.new_label();
+
+        self.push_msg(LunarIR::GetConst(hash));
+        self.push_msg(LunarIR::Copy(1));
+        self.push_msg(LunarIR::Send(case_eq, 1));
+        self.push_msg(LunarIR::JumpNot(end_label));
+        self.push_msg(LunarIR::Pop);
+        self.push_msg(LunarIR::Copy(0));
+        self.walk_string("@lua_key");
+        self.push_msg(LunarIR::Send(iv_get, 1));
+        self.push_msg(LunarIR::JumpNil(new_label));
+        self.push_msg(LunarIR::Store(0));
+        self.push_msg(LunarIR::Jump(end_label));
+
+        self.push_msg(LunarIR::Value(LunarValue::Nil));
+        self.push_msg(LunarIR::Label(new_label));
+        self.push_msg(LunarIR::Pop);
+        self.push_msg(LunarIR::GetConst(object));
+        self.push_msg(LunarIR::Send(new, 0));
+        self.push_msg(LunarIR::Copy(0));
+        self.walk_string("@lua_table");
+        self.push_msg(LunarIR::Copy(3));
+        self.push_msg(LunarIR::Send(iv_set, 2));
+        self.push_msg(LunarIR::Pop);
+        self.push_msg(LunarIR::Copy(1));
+        self.walk_string("@lua_key");
+        self.push_msg(LunarIR::Copy(2));
+        self.push_msg(LunarIR::Send(iv_set, 2));
+        self.push_msg(LunarIR::Pop);
+        self.push_msg(LunarIR::Store(0));
+        self.push_msg(LunarIR::Label(end_label));
+    }
+
+    // a nil key raises an error as in Lua instead of being stored
+    fn walk_set_key(&mut self, key: &Expr) {
+        self.walk_key(key);
+        if matches!(key, Expr::True | Expr::False | Expr::Integer(_) | Expr::Float(_) | Expr::String(_)) {
+            return;
+        }
+        let raise = self.intern_sym("raise");
+        let nil_label = self.new_label();
+        let ok_label = self.new_label();
+
+        self.push_msg(LunarIR::JumpNil(nil_label));
+        self.push_msg(LunarIR::Jump(ok_label));
+        self.push_msg(LunarIR::Label(nil_label));
+        self.push_msg(LunarIR::FunctionCallStart(raise));
+        self.walk_string("index is nil");
+        self.push_msg(LunarIR::FunctionCallEnd);
+        self.push_msg(LunarIR::Pop);
+        self.push_msg(LunarIR::Label(ok_label));
+    }
+
+    // converts a finite Float with an integral value on the top of the stack
+    // to an Integer
+    fn walk_integral_float(&mut self) {
+        let float = self.intern_sym("Float");
+        let case_eq = self.intern_sym("===");
+        let finite = self.intern_sym("finite?");
+        let floor = self.intern_sym("floor");
+        let end_label = self.new_label();
+
+        self.push_msg(LunarIR::GetConst(float));
+        self.push_msg(LunarIR::Copy(1));
+        self.push_msg(LunarIR::Send(case_eq, 1));
+        self.push_msg(LunarIR::JumpNot(end_label));
+        self.push_msg(LunarIR::Pop);
+        self.push_msg(LunarIR::Copy(0));
+        self.push_msg(LunarIR::Send(finite, 0));
+        self.push_msg(LunarIR::JumpNot(end_label));
+        self.push_msg(LunarIR::Pop);
+        // Float#floor gives an Integer, or the Float itself beyond Integer
+        self.push_msg(LunarIR::Copy(0));
+        self.push_msg(LunarIR::Send(floor, 0));
+        self.push_msg(LunarIR::Copy(1));
+        self.push_msg(LunarIR::Copy(1));
+        self.push_msg(LunarIR::BinOp(BinOp::Eq));
+        self.push_msg(LunarIR::JumpNot(end_label));
+        self.push_msg(LunarIR::Pop);
+        self.push_msg(LunarIR::Store(0));
+        self.push_msg(LunarIR::Label(end_label));
+    }
+
+    // Lua shifts are logical and give 0 from 64 bits on, while Ruby's
+    // right shift is arithmetic and a left shift out of range raises
+    fn walk_shift(&mut self, left: bool, amount: &Expr) {
+        let neg = self.intern_sym("-@");
+        match self.literal_integer(amount) {
+            Some(n) => {
+                let n = if left { n } else { n.checked_neg().unwrap_or(i64::MAX) };
+                match n {
+                    0 => {},
+                    1..=63 => {
+                        self.walk_integer(n);
+                        self.walk_shift_left();
+                    },
+                    -63..=-1 => {
+                        self.walk_integer(-n);
+                        self.walk_shift_right();
+                    },
+                    _ => {
+                        self.push_msg(LunarIR::Pop);
+                        self.walk_integer(0);
+                    },
+                }
+            },
+            None => {
+                let right_label = self.new_label();
+                let zero_label = self.new_label();
+                let end_label = self.new_label();
+
+                self.walk_to_integer(amount);
+                if !left {
+                    self.push_msg(LunarIR::Send(neg, 0));
+                }
+                for (limit, op) in [(63, BinOp::Gt), (-63, BinOp::Lt)] {
+                    self.push_msg(LunarIR::Copy(0));
+                    self.walk_integer(limit);
+                    self.push_msg(LunarIR::BinOp(op));
+                    self.push_msg(LunarIR::JumpIf(zero_label));
+                    self.push_msg(LunarIR::Pop);
+                }
+                self.push_msg(LunarIR::Copy(0));
+                self.walk_integer(0);
+                self.push_msg(LunarIR::BinOp(BinOp::Lt));
+                self.push_msg(LunarIR::JumpIf(right_label));
+                self.push_msg(LunarIR::Pop);
+                self.walk_shift_left();
+                self.push_msg(LunarIR::Jump(end_label));
+
+                // unreachable: the branches below start with the value and
+                // the amount on the stack
+                self.push_msg(LunarIR::Value(LunarValue::Nil));
+                self.push_msg(LunarIR::Label(right_label));
+                self.push_msg(LunarIR::Send(neg, 0));
+                self.walk_shift_right();
+                self.push_msg(LunarIR::Jump(end_label));
+
+                self.push_msg(LunarIR::Value(LunarValue::Nil));
+                self.push_msg(LunarIR::Label(zero_label));
+                self.push_msg(LunarIR::Pop);
+                self.walk_integer(0);
+                self.push_msg(LunarIR::Store(0));
+                self.push_msg(LunarIR::Label(end_label));
+            },
+        }
+    }
+
+    // replaces x and n (0 <= n <= 63) with x << n wrapped around 64 bits:
+    // ((x & (MAX >> n)) << n) | (-((x >> (63 - n)) & 1) & MIN)
+    fn walk_shift_left(&mut self) {
+        let and = self.intern_sym("&");
+        let or = self.intern_sym("|");
+        let shl = self.intern_sym("<<");
+        let shr = self.intern_sym(">>");
+        let neg = self.intern_sym("-@");
+
+        self.push_msg(LunarIR::Copy(1));
+        self.walk_integer(i64::MAX);
+        self.push_msg(LunarIR::Copy(2));
+        self.push_msg(LunarIR::Send(shr, 1));
+        self.push_msg(LunarIR::Send(and, 1));
+        self.push_msg(LunarIR::Copy(1));
+        self.push_msg(LunarIR::Send(shl, 1));
+
+        // the bit shifted into the sign bit
+        self.push_msg(LunarIR::Copy(2));
+        self.walk_integer(63);
+        self.push_msg(LunarIR::Copy(3));
+        self.push_msg(LunarIR::BinOp(BinOp::Sub));
+        self.push_msg(LunarIR::Send(shr, 1));
+        self.walk_integer(1);
+        self.push_msg(LunarIR::Send(and, 1));
+        self.push_msg(LunarIR::Send(neg, 0));
+        self.walk_integer(i64::MIN);
+        self.push_msg(LunarIR::Send(and, 1));
+        self.push_msg(LunarIR::Send(or, 1));
+
+        self.push_msg(LunarIR::Store(1));
+        self.push_msg(LunarIR::Pop);
+    }
+
+    // replaces x and n (1 <= n <= 63) with the logical x >> n:
+    // (x >> n) & (MAX >> (n - 1))
+    fn walk_shift_right(&mut self) {
+        let and = self.intern_sym("&");
+        let shr = self.intern_sym(">>");
+
+        self.push_msg(LunarIR::Copy(1));
+        self.push_msg(LunarIR::Copy(1));
+        self.push_msg(LunarIR::Send(shr, 1));
+        self.walk_integer(i64::MAX);
+        self.push_msg(LunarIR::Copy(2));
+        self.push_msg(LunarIR::SubI(1));
+        self.push_msg(LunarIR::Send(shr, 1));
+        self.push_msg(LunarIR::Send(and, 1));
+
+        self.push_msg(LunarIR::Store(1));
+        self.push_msg(LunarIR::Pop);
+    }
+
+    // `#` is the byte length of a String and a border of a table: n where
+    // t[n] is not nil (or n is 0) and t[n+1] is nil
+    fn walk_length(&mut self) {
+        let hash = self.intern_sym("Hash");
+        let string = self.intern_sym("String");
+        let case_eq = self.intern_sym("===");
+        let bytesize = self.intern_sym("bytesize");
+        let size = self.intern_sym("size");
+        let table_label = self.new_label();
+        let other_label = self.new_label();
+        let up_label = self.new_label();
+        let down_label = self.new_label();
+        let dec_label = self.new_label();
+        let end_label = self.new_label();
+
+        self.push_msg(LunarIR::GetConst(hash));
+        self.push_msg(LunarIR::Copy(1));
+        self.push_msg(LunarIR::Send(case_eq, 1));
+        self.push_msg(LunarIR::JumpIf(table_label));
+        self.push_msg(LunarIR::Pop);
+        self.push_msg(LunarIR::GetConst(string));
+        self.push_msg(LunarIR::Copy(1));
+        self.push_msg(LunarIR::Send(case_eq, 1));
+        self.push_msg(LunarIR::JumpNot(other_label));
+        self.push_msg(LunarIR::Pop);
+        self.push_msg(LunarIR::Send(bytesize, 0));
+        self.push_msg(LunarIR::Jump(end_label));
+        // any other Ruby object, e.g. an Array
+        self.push_msg(LunarIR::Label(other_label));
+        self.push_msg(LunarIR::Send(size, 0));
+        self.push_msg(LunarIR::Jump(end_label));
+
+        // starting from the size, which is the border of a sequence, count
+        // up while t[n+1] is set, then down while t[n] is not
+        self.push_msg(LunarIR::Label(table_label));
+        self.push_msg(LunarIR::Copy(0));
+        self.push_msg(LunarIR::Send(size, 0));
+        self.push_msg(LunarIR::Label(up_label));
+        self.push_msg(LunarIR::Copy(1));
+        self.push_msg(LunarIR::Copy(1));
+        self.push_msg(LunarIR::AddI(1));
+        self.push_msg(LunarIR::GetIndex);
+        self.push_msg(LunarIR::JumpNil(down_label));
+        self.push_msg(LunarIR::Pop);
+        self.push_msg(LunarIR::AddI(1));
+        self.push_msg(LunarIR::Jump(up_label));
+        self.push_msg(LunarIR::Label(down_label));
+
+        let loop_label = self.new_label();
+        let done_label = self.new_label();
+        self.push_msg(LunarIR::Label(loop_label));
+        self.push_msg(LunarIR::Copy(0));
+        self.push_msg(LunarIR::Value(LunarValue::Integer(0)));
+        self.push_msg(LunarIR::BinOp(BinOp::Gt));
+        self.push_msg(LunarIR::JumpNot(done_label));
+        self.push_msg(LunarIR::Pop);
+        self.push_msg(LunarIR::Copy(1));
+        self.push_msg(LunarIR::Copy(1));
+        self.push_msg(LunarIR::GetIndex);
+        self.push_msg(LunarIR::JumpNil(dec_label));
+        self.push_msg(LunarIR::Jump(done_label));
+        self.push_msg(LunarIR::Label(dec_label));
+        self.push_msg(LunarIR::Pop);
+        self.push_msg(LunarIR::SubI(1));
+        self.push_msg(LunarIR::Jump(loop_label));
+        self.push_msg(LunarIR::Label(done_label));
+        self.push_msg(LunarIR::Store(0));
+        self.push_msg(LunarIR::Label(end_label));
+    }
+
+    pub fn literal_number(&self, expr: &Expr) -> Option<f64> {
+        match expr {
+            Expr::Integer(n) => Some(*n as f64),
+            Expr::Float(f) => Some(*f),
+            Expr::Unop(op, expr) if op.0.token_type == TokenType::Minus => {
+                self.literal_number(expr).map(|f| -f)
+            },
+            _ => None,
+        }
+    }
+
+    pub fn literal_integer(&self, expr: &Expr) -> Option<i64> {
+        match expr {
+            Expr::Integer(n) => Some(*n),
+            Expr::Unop(op, expr) if op.0.token_type == TokenType::Minus => {
+                self.literal_integer(expr).map(|n| n.wrapping_neg())
+            },
+            _ => None,
+        }
+    }
+
+    // start, limit and step are evaluated once into hidden locals,
+    // then the loop counts at runtime as Lua 5.3 does
+    pub fn walk_numeric_for(&mut self, token: &Token, start: &Expr, limit: &Expr, step: Option<&Expr>, block: &Block) {
+        let loop_label = self.new_label();
+        let end_label = self.new_label();
+        let scope_len = self.indices().scope.len();
+        self.enter_loop(end_label, closure_names(block));
+
+        // a zero step is left to the runtime check, which raises
+        // only when the loop is reached
+        let step_literal = match step {
+            Some(expr) => self.literal_number(expr).filter(|n| *n != 0.0),
+            None => Some(1.0),
+        };
+        // a small integer step is folded into ADDI/SUBI
+        let small_step = match step {
+            Some(expr) => self.literal_integer(expr).filter(|n| *n != 0 && n.abs() <= 255),
+            None => Some(1),
+        };
+
+        self.walk_expr(start);
+        let index = self.declare_local("(for index)");
+        self.push_msg(LunarIR::Local(index));
+        self.walk_expr(limit);
+        let limit = self.declare_local("(for limit)");
+        self.push_msg(LunarIR::Local(limit));
+
+        let mut step_local = None;
+        if small_step.is_none() {
+            // small_step is only None when a step is given
+            let step = step.unwrap();
+            self.walk_expr(step);
+            let idx = self.declare_local("(for step)");
+            self.push_msg(LunarIR::Local(idx));
+            step_local = Some(idx);
+
+            if step_literal.is_none() {
+                let ok_label = self.new_label();
+                self.push_msg(LunarIR::GetLocal(idx));
+                self.push_msg(LunarIR::Value(LunarValue::Integer(0)));
+                self.push_msg(LunarIR::BinOp(BinOp::Eq));
+                self.push_msg(LunarIR::JumpNot(ok_label));
+                self.push_msg(LunarIR::Pop);
+                let sym = self.intern_sym("raise");
+                self.push_msg(LunarIR::FunctionCallStart(sym));
+                self.walk_string("'for' step is zero");
+                self.push_msg(LunarIR::FunctionCallEnd);
+                self.push_msg(LunarIR::Pop);
+                self.push_msg(LunarIR::Label(ok_label));
+            }
+
+            // index + (step - step) turns the index into a Float
+            // when the step is a Float, as Lua does
+            self.push_msg(LunarIR::GetLocal(index));
+            self.push_msg(LunarIR::GetLocal(idx));
+            self.push_msg(LunarIR::GetLocal(idx));
+            self.push_msg(LunarIR::BinOp(BinOp::Sub));
+            self.push_msg(LunarIR::BinOp(BinOp::Add));
+            self.push_msg(LunarIR::SetLocal(index));
+        }
+
+        self.push_msg(LunarIR::Label(loop_label));
+        match step_literal {
+            Some(n) => {
+                self.push_msg(LunarIR::GetLocal(index));
+                self.push_msg(LunarIR::GetLocal(limit));
+                let op = if n > 0.0 { BinOp::Le } else { BinOp::Ge };
+                self.push_msg(LunarIR::BinOp(op));
+            },
+            None => {
+                // the direction depends on the sign of the step
+                let negative_label = self.new_label();
+                let test_label = self.new_label();
+                self.push_msg(LunarIR::GetLocal(step_local.unwrap()));
+                self.push_msg(LunarIR::Value(LunarValue::Integer(0)));
+                self.push_msg(LunarIR::BinOp(BinOp::Gt));
+                self.push_msg(LunarIR::JumpNot(negative_label));
+                self.push_msg(LunarIR::Pop);
+                self.push_msg(LunarIR::GetLocal(index));
+                self.push_msg(LunarIR::GetLocal(limit));
+                self.push_msg(LunarIR::BinOp(BinOp::Le));
+                self.push_msg(LunarIR::Jump(test_label));
+                self.push_msg(LunarIR::Pop);
+                self.push_msg(LunarIR::Label(negative_label));
+                self.push_msg(LunarIR::GetLocal(index));
+                self.push_msg(LunarIR::GetLocal(limit));
+                self.push_msg(LunarIR::BinOp(BinOp::Ge));
+                self.push_msg(LunarIR::Label(test_label));
+            },
+        }
+        self.push_msg(LunarIR::JumpNot(end_label));
+        self.push_msg(LunarIR::Pop);
+
+        // the visible variable is a copy of the index,
+        // so assigning to it does not change the iteration
+        self.push_msg(LunarIR::GetLocal(index));
+        self.define_local(&token.lexeme);
+
+        self.walk_scoped_block(block);
+        self.leave_loop();
+
+        self.push_msg(LunarIR::GetLocal(index));
+        match (small_step, step_local) {
+            (Some(n), _) if n >= 0 => self.push_msg(LunarIR::AddI(n as u8)),
+            (Some(n), _) => self.push_msg(LunarIR::SubI((-n) as u8)),
+            (None, Some(idx)) => {
+                self.push_msg(LunarIR::GetLocal(idx));
+                self.push_msg(LunarIR::BinOp(BinOp::Add));
+            },
+            (None, None) => unreachable!(),
+        }
+        self.push_msg(LunarIR::SetLocal(index));
+        self.push_msg(LunarIR::Jump(loop_label));
+        self.push_msg(LunarIR::Label(end_label));
+
+        self.close_scope(scope_len);
+    }
+
+    // `pairs(t)`, `ipairs(t)` and `next, t` iterate without calling anything
+    fn iterator_intrinsic<'a>(&self, exprs: &'a [Expr]) -> Option<(&'static str, &'a Expr)> {
+        let global_name = |expr: &Expr| match expr {
+            Expr::PrefixExp(prefix) => self.global_path(prefix),
+            _ => None,
+        };
+
+        match exprs {
+            [Expr::PrefixExp(PrefixExp::PrefixCall(FunctionCall(func, None, Args::ArgsList(args))))] if args.0.len() == 1 => {
+                match self.global_path(func).as_deref() {
+                    Some("pairs") => Some(("pairs", &args.0[0])),
+                    Some("ipairs") => Some(("ipairs", &args.0[0])),
+                    _ => None,
+                }
+            },
+            [next, table] if global_name(next).as_deref() == Some("next") => Some(("pairs", table)),
+            _ => None,
+        }
+    }
+
+    // binds the values on the stack to the loop variables
+    fn bind_loop_vars(&mut self, names: &[Token], values: usize) {
+        for _ in names.len()..values {
+            self.push_msg(LunarIR::Pop);
+        }
+        for _ in values..names.len() {
+            self.push_msg(LunarIR::Value(LunarValue::Nil));
+        }
+        for name in names.iter() {
+            self.define_local(&name.lexeme);
+        }
+    }
+
+    pub fn walk_generic_for(&mut self, names: &NameList, exprs: &ExprList, block: &Block) {
+        let loop_label = self.new_label();
+        let end_label = self.new_label();
+        let scope_len = self.indices().scope.len();
+        self.enter_loop(end_label, closure_names(block));
+        let names = &names.0;
+
+        match self.iterator_intrinsic(&exprs.0) {
+            Some(("pairs", table)) => {
+                // iterates over a snapshot of the keys, skipping
+                // the ones removed during the traversal
+                self.walk_expr(table);
+                let state = self.declare_local("(for state)");
+                self.push_msg(LunarIR::Local(state));
+                self.push_msg(LunarIR::GetLocal(state));
+                let sym = self.intern_sym("keys");
+                self.push_msg(LunarIR::Send(sym, 0));
+                let keys = self.declare_local("(for keys)");
+                self.push_msg(LunarIR::Local(keys));
+                self.push_msg(LunarIR::Value(LunarValue::Integer(0)));
+                let index = self.declare_local("(for index)");
+                self.push_msg(LunarIR::Local(index));
+
+                self.push_msg(LunarIR::Label(loop_label));
+                self.push_msg(LunarIR::GetLocal(index));
+                self.push_msg(LunarIR::GetLocal(keys));
+                let sym = self.intern_sym("size");
+                self.push_msg(LunarIR::Send(sym, 0));
+                self.push_msg(LunarIR::BinOp(BinOp::Lt));
+                self.push_msg(LunarIR::JumpNot(end_label));
+                self.push_msg(LunarIR::Pop);
+
+                self.push_msg(LunarIR::GetLocal(keys));
+                self.push_msg(LunarIR::GetLocal(index));
+                self.push_msg(LunarIR::GetIndex);
+                self.push_msg(LunarIR::GetLocal(index));
+                self.push_msg(LunarIR::AddI(1));
+                self.push_msg(LunarIR::SetLocal(index));
+                self.push_msg(LunarIR::GetLocal(state));
+                self.push_msg(LunarIR::Copy(1));
+                self.push_msg(LunarIR::GetIndex);
+                self.push_msg(LunarIR::JumpNil(loop_label));
+
+                // a table key is stored as the Object made by walk_table_key
+                let iv_get = self.intern_sym("instance_variable_get");
+                let key_label = self.new_label();
+                self.push_msg(LunarIR::Copy(1));
+                self.walk_string("@lua_table");
+                self.push_msg(LunarIR::Send(iv_get, 1));
+                self.push_msg(LunarIR::JumpNil(key_label));
+                self.push_msg(LunarIR::Store(1));
+                self.push_msg(LunarIR::Value(LunarValue::Nil));
+                self.push_msg(LunarIR::Label(key_label));
+                self.push_msg(LunarIR::Pop);
+                self.bind_loop_vars(names, 2);
+            },
+            Some(("ipairs", table)) => {
+                // runs from t[1] up to the first nil
+                self.walk_expr(table);
+                let state = self.declare_local("(for state)");
+                self.push_msg(LunarIR::Local(state));
+                self.push_msg(LunarIR::Value(LunarValue::Integer(0)));
+                let index = self.declare_local("(for index)");
+                self.push_msg(LunarIR::Local(index));
+
+                self.push_msg(LunarIR::Label(loop_label));
+                self.push_msg(LunarIR::GetLocal(index));
+                self.push_msg(LunarIR::AddI(1));
+                self.push_msg(LunarIR::SetLocal(index));
+                self.push_msg(LunarIR::GetLocal(index));
+                self.push_msg(LunarIR::GetLocal(state));
+                self.push_msg(LunarIR::GetLocal(index));
+                self.push_msg(LunarIR::GetIndex);
+                self.push_msg(LunarIR::JumpNil(end_label));
+                self.bind_loop_vars(names, 2);
+            },
+            _ => {
+                // for v1, ..., vn in f, s, var
+                // calls f(s, var) until v1 becomes nil
+                self.walk_exprs_adjusted(&exprs.0, 3);
+                let generator = self.declare_local("(for generator)");
+                let state = self.declare_local("(for state)");
+                let control = self.declare_local("(for control)");
+                self.push_msg(LunarIR::Local(generator));
+                self.push_msg(LunarIR::Local(state));
+                self.push_msg(LunarIR::Local(control));
+
+                self.push_msg(LunarIR::Label(loop_label));
+                self.push_msg(LunarIR::GetLocal(generator));
+                self.push_msg(LunarIR::GetLocal(state));
+                self.push_msg(LunarIR::GetLocal(control));
+                let sym = self.intern_sym("call");
+                self.push_msg(LunarIR::Send(sym, 2));
+                self.push_msg(LunarIR::Unpack(names.len()));
+                self.push_msg(LunarIR::Copy(names.len() - 1));
+                self.push_msg(LunarIR::JumpNil(end_label));
+                self.push_msg(LunarIR::SetLocal(control));
+                self.bind_loop_vars(names, names.len());
+            },
+        }
+
+        self.walk_scoped_block(block);
+        self.leave_loop();
+
+        self.push_msg(LunarIR::Jump(loop_label));
+        self.push_msg(LunarIR::Label(end_label));
+
+        self.close_scope(scope_len);
+    }
+
     pub fn walk_prefixexpr(&mut self, prefix_expr: &PrefixExp) {
         match prefix_expr {
             PrefixExp::PrefixVar(var) => {
@@ -178,10 +1972,14 @@ impl Walker {
                 let var = var.as_ref();
                 self.walk_var(var);
             },
-            _ => {
-                // Handle other types of prefix expressions
-                panic!("Unsupported prefix expression: {:?}", prefix_expr);
-            }
+            PrefixExp::PrefixParen(expr) => {
+                self.walk_expr(expr);
+            },
+            PrefixExp::PrefixCall(function_call) => {
+                // only the first result is used
+                self.walk_function_call(function_call);
+                self.push_msg(LunarIR::Unpack(1));
+            },
         }
     }
 
@@ -189,32 +1987,469 @@ impl Walker {
         match var {
             Var::VarName(name) => {
                 // Handle variable name
-                let name = match name.token_type {
-                    TokenType::Name => {
-                        name.lexeme.clone()
-                    },
-                    _ => {
-                        // Handle other types of variable names
-                        panic!("Unsupported variable name: {:?}", name);
-                    }
-                };
-                self.push_msg(LunarIR::StoreSym(
-                    self.idx_of_ireps[&self.current_irep].syms,
-                    name.clone().try_into().unwrap(),
-                ));
-                self.idx_of_ireps.get_mut(&self.current_irep).unwrap().syms += 1;
+                if name.token_type != TokenType::Name {
+                    // Handle other types of variable names
+                    panic!("Unsupported variable name: {:?}", name);
+                }
+                self.walk_get_name(name);
+            },
+            Var::VarIdx(..) | Var::VarMember(..) => {
+                self.walk_index_target(var);
+                self.push_msg(LunarIR::GetIndex);
+            },
+        }
+    }
+
+    // pushes the table and the key of `t[k]` or `t.name`, or nothing for a
+    // name, returning whether they were pushed
+    pub fn walk_index_target(&mut self, var: &Var) -> bool {
+        match var {
+            Var::VarName(_) => return false,
+            Var::VarIdx(prefix, key) => {
+                self.walk_prefixexpr(prefix);
+                self.walk_key(key);
+            },
+            Var::VarMember(prefix, name) => {
+                self.walk_prefixexpr(prefix);
+                self.walk_string(&name.lexeme);
+            },
+        }
+        true
+    }
+
+    // as walk_index_target, but the key of an assignment must not be nil
+    fn walk_assign_target(&mut self, var: &Var) -> bool {
+        match var {
+            Var::VarIdx(prefix, key) => {
+                self.walk_prefixexpr(prefix);
+                self.walk_set_key(key);
+                true
+            },
+            _ => self.walk_index_target(var),
+        }
+    }
+
+    // the rest argument holding `...`; not visible from nested functions
+    fn vararg_local(&self) -> Option<usize> {
+        self.idx_of_ireps[&self.current_irep]
+            .scope
+            .iter()
+            .find(|(local, _)| local == "...")
+            .map(|(_, idx)| *idx)
+    }
+
+    // the dotted name of a global not shadowed by a local, e.g. `table.pack`
+    fn global_path(&self, prefix: &PrefixExp) -> Option<String> {
+        match prefix {
+            PrefixExp::PrefixVar(var) => match var.as_ref() {
+                Var::VarName(name) if self.resolve_name(&name.lexeme).is_none() => Some(name.lexeme.clone()),
+                Var::VarMember(prefix, name) => {
+                    self.global_path(prefix).map(|path| format!("{}.{}", path, name.lexeme))
+                },
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // locals of enclosing functions are captured as upvalues
+    pub fn walk_get_name(&mut self, name: &Token) {
+        match self.resolve_name(&name.lexeme) {
+            Some((idx, up)) => {
+                self.walk_get_slot(idx, up);
+                if self.indices_up(up).boxed.contains(&idx) {
+                    self.push_msg(LunarIR::Value(LunarValue::Integer(0)));
+                    self.push_msg(LunarIR::GetIndex);
+                }
+            },
+            None => self.walk_get_global(&name.lexeme),
+        }
+    }
+
+    pub fn walk_set_name(&mut self, name: &Token) {
+        match self.resolve_name(&name.lexeme) {
+            Some((idx, up)) if self.indices_up(up).boxed.contains(&idx) => {
+                // box[0] = value
+                let sym = self.intern_sym("[]=");
+                self.walk_get_slot(idx, up);
+                self.push_msg(LunarIR::Value(LunarValue::Integer(0)));
+                self.push_msg(LunarIR::Copy(2));
+                self.push_msg(LunarIR::Send(sym, 2));
+                self.push_msg(LunarIR::Pop);
+                self.push_msg(LunarIR::Pop);
+            },
+            Some((idx, 0)) => self.push_msg(LunarIR::SetLocal(idx)),
+            Some((idx, up)) => {
+                self.capture_local(idx, up);
+                self.push_msg(LunarIR::SetUpvar(idx, up - 1));
+            },
+            None => self.walk_set_global(&name.lexeme),
+        }
+    }
+
+    // pushes the value of a local slot `up` ireps above
+    fn walk_get_slot(&mut self, idx: usize, up: usize) {
+        if up == 0 {
+            self.push_msg(LunarIR::GetLocal(idx));
+        } else {
+            self.capture_local(idx, up);
+            self.push_msg(LunarIR::GetUpvar(idx, up - 1));
+        }
+    }
+
+    // pops the value on the top of the stack into the variable
+    pub fn walk_assign_var(&mut self, var: &Var) {
+        match var {
+            Var::VarName(name) if name.token_type == TokenType::Name => {
+                self.walk_set_name(name);
             },
             _ => {
                 // Handle other types of variables
-                panic!("Unsupported variable: {:?}", var);
+                panic!("Unsupported assignment target: {:?}", var);
             }
         }
     }
 
+    pub fn walk_get_global(&mut self, name: &str) {
+        match self.options.globals {
+            GlobalsMode::GlobalVariable => {
+                let sym = self.intern_sym(&format!("${}", name));
+                self.push_msg(LunarIR::GetGlobal(sym));
+            },
+            GlobalsMode::GlobalTable => {
+                let sym = self.intern_sym("$_G");
+                self.push_msg(LunarIR::GetGlobal(sym));
+                if name != "_G" {
+                    self.walk_string(name);
+                    self.push_msg(LunarIR::GetIndex);
+                }
+            },
+        }
+    }
+
+    pub fn walk_set_global(&mut self, name: &str) {
+        match self.options.globals {
+            GlobalsMode::GlobalVariable => {
+                let sym = self.intern_sym(&format!("${}", name));
+                self.push_msg(LunarIR::SetGlobal(sym));
+            },
+            GlobalsMode::GlobalTable => {
+                let sym = self.intern_sym("$_G");
+                self.push_msg(LunarIR::GetGlobal(sym));
+                self.walk_string(name);
+                self.push_msg(LunarIR::Copy(2));
+                let delete = self.intern_sym("delete");
+                self.push_msg(LunarIR::SetIndex(delete));
+                self.push_msg(LunarIR::Pop);
+            },
+        }
+    }
+
+    // a goto to a label not reached yet is checked when the label is
+    pub fn walk_goto(&mut self, name: &Token) {
+        if self.options.version == LuaVersion::Lua51 {
+            self.error("goto is not available in Lua 5.1", name.line);
+        }
+        let Some(target) = self.find_label(&name.lexeme).cloned() else {
+            self.error(format!("no visible label '{}' for <goto>", name.lexeme), name.line);
+            return;
+        };
+        if !target.reached {
+            let active = self.indices().scope.len();
+            self.indices().pending_gotos.push(PendingGoto {
+                label: target.label,
+                name: name.lexeme.clone(),
+                line: name.line,
+                active,
+            });
+        }
+        self.push_msg(LunarIR::Jump(target.label));
+    }
+
+    // labels are declared by their block, so the innermost one has it
+    pub fn walk_label(&mut self, name: &Token) {
+        let indices = self.indices();
+        let block = indices.label_blocks.last_mut().unwrap();
+        let scope_len = block.scope_len;
+        let target = block.labels.get_mut(&name.lexeme).unwrap();
+        target.reached = true;
+        let label = target.label;
+        let active = if target.at_end { scope_len } else { indices.scope.len() };
+
+        let skipped = indices.pending_gotos.iter().find(|goto| goto.label == label && goto.active < active);
+        if let Some(goto) = skipped {
+            let (local, _) = &indices.scope[goto.active];
+            let message = format!("<goto {}> jumps into the scope of local '{}'", goto.name, local);
+            let line = goto.line;
+            self.error(message, line);
+        }
+        self.indices().pending_gotos.retain(|goto| goto.label != label);
+        self.push_msg(LunarIR::Label(label));
+    }
+
     pub fn walk_laststat(&mut self, last_stat: &LastStat) {
         match last_stat {
-            LastStat::Return(_ret) => todo!(),
-            LastStat::Break => todo!(),
+            // a single value is returned as it is and multiple values as an
+            // Array, so callers wanting one value take it with AREF
+            LastStat::Return(exprs) => {
+                match exprs.as_ref().map(|exprs| exprs.0.as_slice()) {
+                    None | Some([]) => self.push_msg(LunarIR::Value(LunarValue::Nil)),
+                    // results of a tail call are passed through
+                    Some([Expr::PrefixExp(PrefixExp::PrefixCall(function_call))]) => {
+                        self.walk_function_call(function_call);
+                    },
+                    Some([expr]) if !self.is_multi(expr) => self.walk_expr(expr),
+                    Some(exprs) => self.walk_array(exprs),
+                }
+                self.push_msg(LunarIR::Return);
+            },
+            LastStat::Break(token) => {
+                match self.indices().loop_exits.last().copied() {
+                    Some(label) => self.push_msg(LunarIR::Jump(label)),
+                    None => self.error("break outside a loop", token.line),
+                }
+            },
+        }
+    }
+}
+
+fn is_constant_field(field: &Field) -> bool {
+    let is_constant = |expr: &Expr| {
+        matches!(expr, Expr::True | Expr::False | Expr::Integer(_) | Expr::Float(_) | Expr::String(_) | Expr::Function(_) | Expr::TableConstructor(_))
+    };
+    match field {
+        Field::UniExp(value) | Field::AssignName(_, value) => is_constant(value),
+        Field::AssignIdx(key, value) => is_constant(key) && is_constant(value),
+    }
+}
+
+// names used inside the functions of a block, which may be its locals
+fn closure_names(block: &Block) -> HashSet<String> {
+    let mut names = HashSet::new();
+    collect_names(Node::Block(block), false, &mut names);
+    names
+}
+
+enum Node<'a> {
+    Block(&'a Block),
+    Expr(&'a Expr),
+    Var(&'a Var),
+    Prefix(&'a PrefixExp),
+    Call(&'a FunctionCall),
+}
+
+// adds the names read or assigned in the node, once inside a function
+fn collect_names(node: Node, in_function: bool, names: &mut HashSet<String>) {
+    let exprs = |exprs: &[Expr], names: &mut HashSet<String>| {
+        for expr in exprs {
+            collect_names(Node::Expr(expr), in_function, names);
         }
+    };
+    match node {
+        Node::Block(Block(Chunk(stats, last))) => {
+            for stat in stats {
+                match stat {
+                    Stat::Assign(VarList(vars), ExprList(values)) => {
+                        for var in vars {
+                            collect_names(Node::Var(var), in_function, names);
+                        }
+                        exprs(values, names);
+                    },
+                    Stat::FunctionCall(call) => collect_names(Node::Call(call), in_function, names),
+                    Stat::Do(block) => collect_names(Node::Block(block), in_function, names),
+                    Stat::While(cond, block) | Stat::Repeat(cond, block) => {
+                        collect_names(Node::Expr(cond), in_function, names);
+                        collect_names(Node::Block(block), in_function, names);
+                    },
+                    Stat::If(cond, block, elseifs, else_block) => {
+                        collect_names(Node::Expr(cond), in_function, names);
+                        collect_names(Node::Block(block), in_function, names);
+                        for (cond, block) in elseifs {
+                            collect_names(Node::Expr(cond), in_function, names);
+                            collect_names(Node::Block(block), in_function, names);
+                        }
+                        if let Some(block) = else_block {
+                            collect_names(Node::Block(block), in_function, names);
+                        }
+                    },
+                    Stat::For(_, start, limit, step, block) => {
+                        collect_names(Node::Expr(start), in_function, names);
+                        collect_names(Node::Expr(limit), in_function, names);
+                        if let Some(step) = step {
+                            collect_names(Node::Expr(step), in_function, names);
+                        }
+                        collect_names(Node::Block(block), in_function, names);
+                    },
+                    Stat::ForIn(_, ExprList(values), block) => {
+                        exprs(values, names);
+                        collect_names(Node::Block(block), in_function, names);
+                    },
+                    Stat::Function(FuncName(path, _), FuncBody(_, block)) => {
+                        if in_function {
+                            names.insert(path[0].lexeme.clone());
+                        }
+                        collect_names(Node::Block(block), true, names);
+                    },
+                    Stat::LocalFunction(_, FuncBody(_, block)) => collect_names(Node::Block(block), true, names),
+                    Stat::LocalDeclVar(_, Some(ExprList(values))) => exprs(values, names),
+                    Stat::LocalDeclVar(_, None) | Stat::Goto(_) | Stat::Label(_) => {},
+                }
+            }
+            if let Some(LastStat::Return(Some(ExprList(values)))) = last {
+                exprs(values, names);
+            }
+        },
+        Node::Expr(expr) => match expr {
+            Expr::Function(Function(FuncBody(_, block))) => collect_names(Node::Block(block), true, names),
+            Expr::PrefixExp(prefix) => collect_names(Node::Prefix(prefix), in_function, names),
+            Expr::TableConstructor(TableConstructor(FieldList(fields))) => {
+                for field in fields {
+                    match field {
+                        Field::AssignIdx(key, value) => {
+                            collect_names(Node::Expr(key), in_function, names);
+                            collect_names(Node::Expr(value), in_function, names);
+                        },
+                        Field::AssignName(_, value) | Field::UniExp(value) => {
+                            collect_names(Node::Expr(value), in_function, names);
+                        },
+                    }
+                }
+            },
+            Expr::ExprBinop(lhs, _, rhs) => {
+                collect_names(Node::Expr(lhs), in_function, names);
+                collect_names(Node::Expr(rhs), in_function, names);
+            },
+            Expr::Unop(_, expr) => collect_names(Node::Expr(expr), in_function, names),
+            _ => {},
+        },
+        Node::Var(var) => match var {
+            Var::VarName(name) => {
+                if in_function {
+                    names.insert(name.lexeme.clone());
+                }
+            },
+            Var::VarIdx(prefix, key) => {
+                collect_names(Node::Prefix(prefix), in_function, names);
+                collect_names(Node::Expr(key), in_function, names);
+            },
+            Var::VarMember(prefix, _) => collect_names(Node::Prefix(prefix), in_function, names),
+        },
+        Node::Prefix(prefix) => match prefix {
+            PrefixExp::PrefixVar(var) => collect_names(Node::Var(var), in_function, names),
+            PrefixExp::PrefixCall(call) => collect_names(Node::Call(call), in_function, names),
+            PrefixExp::PrefixParen(expr) => collect_names(Node::Expr(expr), in_function, names),
+        },
+        Node::Call(FunctionCall(prefix, _, args)) => {
+            collect_names(Node::Prefix(prefix), in_function, names);
+            match args {
+                Args::ArgsList(ExprList(values)) => exprs(values, names),
+                Args::ArgsTable(table) => {
+                    let table = Expr::TableConstructor(table.clone());
+                    collect_names(Node::Expr(&table), in_function, names);
+                },
+                Args::ArgsString(_) => {},
+            }
+        },
+    }
+}
+
+// operands of `a .. b .. c` from left to right
+fn concat_operands<'a>(expr: &'a Expr, operands: &mut Vec<&'a Expr>) {
+    match expr {
+        Expr::ExprBinop(lhs, op, rhs) if op.0.token_type == TokenType::Concat => {
+            concat_operands(lhs, operands);
+            concat_operands(rhs, operands);
+        },
+        _ => operands.push(expr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lua::loader::load_string;
+
+    fn walk(source: &str) -> Walker {
+        let program = load_string(source).unwrap();
+        let mut walker = Walker::new();
+        walker.walk(&program.block).unwrap();
+        walker
+    }
+
+    fn walk_error(source: &str) -> CompileError {
+        let program = load_string(source).unwrap();
+        Walker::new().walk(&program.block).unwrap_err()
+    }
+
+    #[test]
+    fn goto_into_the_scope_of_a_local_is_an_error() {
+        let error = walk_error("do\n  goto skip\n  local x = 1\n  ::skip::\n  print(x)\nend");
+        assert_eq!(error.message, "<goto skip> jumps into the scope of local 'x'");
+        assert_eq!(error.line, Some(2));
+
+        // the scope of a local ends at the last statement of its block
+        walk("do\n  goto skip\n  local x = 1\n  ::skip::\nend");
+    }
+
+    #[test]
+    fn labels_must_be_unique_and_visible() {
+        let error = walk_error("::top::\ndo\n  ::top::\nend");
+        assert_eq!(error.message, "label 'top' already defined on line 1");
+        assert_eq!(error.line, Some(3));
+
+        let error = walk_error("do ::inner:: end\ngoto inner");
+        assert_eq!(error.message, "no visible label 'inner' for <goto>");
+        assert_eq!(error.line, Some(2));
+
+        // a label of a function is not visible from a nested one
+        let error = walk_error("::out::\nlocal f = function() goto out end");
+        assert_eq!(error.message, "no visible label 'out' for <goto>");
+    }
+
+    #[test]
+    fn break_outside_a_loop_is_an_error() {
+        let error = walk_error("local x = 1\nbreak");
+        assert_eq!(error.message, "break outside a loop");
+        assert_eq!(error.line, Some(2));
+
+        let error = walk_error("while true do\n  local f = function() break end\nend");
+        assert_eq!(error.message, "break outside a loop");
+        walk("while true do\n  do break end\nend");
+    }
+
+    #[test]
+    fn long_strings_are_split_into_pool_entries() {
+        let length = POOL_STRING_MAX + 100;
+        let walker = walk(&format!("local s = \"{}\"", "a".repeat(length)));
+        let pieces: Vec<usize> = walker
+            .msg_stack
+            .iter()
+            .filter_map(|msg| match msg {
+                LunarIR::PoolString(_, bytes) => Some(bytes.len()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(pieces, vec![POOL_STRING_MAX, 100]);
+        let concats = walker.msg_stack.iter().filter(|msg| matches!(msg, LunarIR::BinOp(BinOp::Concat))).count();
+        assert_eq!(concats, 1);
+    }
+
+    fn sends(walker: &Walker, name: &str) -> usize {
+        let sym = walker.msg_stack.iter().find_map(|msg| match msg {
+            LunarIR::StoreSym(sym, sym_name) if sym_name == name => Some(*sym),
+            _ => None,
+        });
+        walker.msg_stack.iter().filter(|msg| matches!(msg, LunarIR::Send(s, _) if Some(*s) == sym)).count()
+    }
+
+    #[test]
+    fn table_keys_are_replaced_by_identity_objects() {
+        let walker = walk("local a, t = {}, {} t[a] = 1 print(t[a])");
+        // one lookup of @lua_key and two sets on first use, per key
+        assert_eq!(sends(&walker, "instance_variable_set"), 4);
+        assert_eq!(sends(&walker, "instance_variable_get"), 2);
+
+        let walker = walk("local t = {} t[\"a\"] = 1 t[2.0] = 2 print(t.a)");
+        assert_eq!(sends(&walker, "instance_variable_get"), 0);
     }
 }
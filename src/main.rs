@@ -1,7 +1,7 @@
 extern crate lunar_lang;
 use clap::*;
 
-const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 fn main() {
     let mut command = command!()
@@ -13,12 +13,31 @@ fn main() {
                 .about("Compile a Lua source file to an mruby binary")
                 .arg(arg!(-o --output <OUTPUT> "Output mruby binary file"))
                 .arg(arg!(--debug "Enable debug information"))
+                .arg(
+                    arg!(--globals <MODE> "Where Lua globals are stored")
+                        .value_parser(["gvar", "table"])
+                        .default_value("gvar"),
+                )
+                .arg(
+                    arg!(--"lua-version" <VERSION> "Lua version whose number semantics are followed")
+                        .value_parser(["5.1", "5.3", "5.4"])
+                        .default_value("5.4"),
+                )
                 .arg(arg!([lua_script] "Lua source file to compile")),
         );
     let matches = command.clone().get_matches();
 
     if let Some(matches) = matches.subcommand_matches("compile") {
         let debug = matches.get_flag("debug");
+        let globals = match matches.get_one::<String>("globals").map(|s| s.as_str()) {
+            Some("table") => lunar_lang::lua::walker::GlobalsMode::GlobalTable,
+            _ => lunar_lang::lua::walker::GlobalsMode::GlobalVariable,
+        };
+        let version = match matches.get_one::<String>("lua-version").map(|s| s.as_str()) {
+            Some("5.1") => lunar_lang::lua::walker::LuaVersion::Lua51,
+            // 5.3 numbers behave as in 5.4
+            _ => lunar_lang::lua::walker::LuaVersion::Lua54,
+        };
         let lua_path = matches.get_one::<String>("lua_script").expect("require lua script");
         let lua_path = lua_path.to_owned();
         let output = if let Some(value) = matches.get_one::<String>("output") {
@@ -33,15 +52,25 @@ fn main() {
 
         match lunar_lang::lua::loader::load_file(&lua_path) {
             Ok(program) => {
-                let mut walker = lunar_lang::lua::walker::Walker::new();
-                walker.walk(&program.block);
+                let options = lunar_lang::lua::walker::CompileOptions { globals, version };
+                let mut walker = lunar_lang::lua::walker::Walker::with_options(options);
+                if let Err(e) = walker.walk(&program.block) {
+                    eprintln!("Error compiling program: {}", e);
+                    std::process::exit(1);
+                }
                 if debug {
                     for (i, msg) in walker.msg_stack.iter().enumerate() {
                         eprintln!("LUNARIR: {:<04}: {:?}", i, msg);
                     }
                 }
 
-                let mruby = lunar_lang::rite::transformer::transform(&walker.msg_stack);
+                let mruby = match lunar_lang::rite::transformer::transform(&walker.msg_stack) {
+                    Ok(mruby) => mruby,
+                    Err(e) => {
+                        eprintln!("Error compiling program: {}", e);
+                        std::process::exit(1);
+                    },
+                };
                 if debug {
                     for (i, rep) in mruby.iter().enumerate() {
                         eprintln!("IREP: {:<04}: rlen = {}", i, rep.borrow().rep_len);
@@ -11,26 +11,43 @@ impl Bytecode {
         Bytecode { op, operand }
     }
 
+    // operands above 0xff take 16 bits, announced by an OP_EXT1 (the first),
+    // OP_EXT2 (the second) or OP_EXT3 (both) prefix
     pub fn to_bytes_vec(&self) -> Vec<u8> {
-        let mut bytes = vec![self.op as u8];
+        let (first, second): (u16, u16) = match self.operand {
+            Operand::B(a) | Operand::BS(a, _) | Operand::BSS(a, _, _) => (a, 0),
+            Operand::BB(a, b) | Operand::BBB(a, b, _) => (a, b),
+            Operand::Z | Operand::S(_) | Operand::W(_) => (0, 0),
+        };
+        let ext = match (first > 0xff, second > 0xff) {
+            (false, false) => None,
+            (true, false) => Some(OpCode::EXT1),
+            (false, true) => Some(OpCode::EXT2),
+            (true, true) => Some(OpCode::EXT3),
+        };
+        let mut bytes = Vec::new();
+        if let Some(ext) = ext {
+            bytes.push(ext as u8);
+        }
+        bytes.push(self.op as u8);
         match self.operand {
             Operand::Z => {}
-            Operand::B(b) => bytes.push(b),
+            Operand::B(b) => push_operand(&mut bytes, b, first > 0xff),
             Operand::BB(b1, b2) => {
-                bytes.push(b1);
-                bytes.push(b2);
+                push_operand(&mut bytes, b1, first > 0xff);
+                push_operand(&mut bytes, b2, second > 0xff);
             }
             Operand::BBB(b1, b2, b3) => {
-                bytes.push(b1);
-                bytes.push(b2);
+                push_operand(&mut bytes, b1, first > 0xff);
+                push_operand(&mut bytes, b2, second > 0xff);
                 bytes.push(b3);
             }
             Operand::BS(b1, s) => {
-                bytes.push(b1);
+                push_operand(&mut bytes, b1, first > 0xff);
                 bytes.extend_from_slice(&s.to_be_bytes());
             }
             Operand::BSS(b1, s1, s2) => {
-                bytes.push(b1);
+                push_operand(&mut bytes, b1, first > 0xff);
                 bytes.extend_from_slice(&s1.to_be_bytes());
                 bytes.extend_from_slice(&s2.to_be_bytes());
             }
@@ -47,6 +64,14 @@ impl Bytecode {
     }
 }
 
+fn push_operand(bytes: &mut Vec<u8>, value: u16, wide: bool) {
+    if wide {
+        bytes.extend_from_slice(&value.to_be_bytes());
+    } else {
+        bytes.push(value as u8);
+    }
+}
+
 #[allow(non_camel_case_types)]
 #[repr(u8)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -164,11 +189,40 @@ pub enum OpCode {
 #[derive(Debug, Copy, Clone)]
 pub enum Operand {
     Z,
-    B(u8),
-    BB(u8, u8),
-    BBB(u8, u8, u8),
-    BS(u8, u16),
-    BSS(u8, u16, u16),
+    // the B operands that may be extended to 16 bits are u16
+    B(u16),
+    BB(u16, u16),
+    BBB(u16, u16, u8),
+    BS(u16, u16),
+    BSS(u16, u16, u16),
     S(u16),
     W(u32), // u24 in real layout
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(op: OpCode, operand: Operand) -> Vec<u8> {
+        Bytecode::new(op, operand).to_bytes_vec()
+    }
+
+    #[test]
+    fn narrow_operands_have_no_prefix() {
+        assert_eq!(bytes(OpCode::STRING, Operand::BB(1, 255)), vec![OpCode::STRING as u8, 1, 255]);
+    }
+
+    #[test]
+    fn wide_operands_are_prefixed() {
+        let string = OpCode::STRING as u8;
+        assert_eq!(bytes(OpCode::STRING, Operand::BB(256, 1)), vec![OpCode::EXT1 as u8, string, 1, 0, 1]);
+        assert_eq!(bytes(OpCode::STRING, Operand::BB(1, 599)), vec![OpCode::EXT2 as u8, string, 1, 2, 87]);
+        assert_eq!(bytes(OpCode::STRING, Operand::BB(600, 599)), vec![OpCode::EXT3 as u8, string, 2, 88, 2, 87]);
+    }
+
+    #[test]
+    fn third_operand_stays_narrow() {
+        let send = OpCode::SEND as u8;
+        assert_eq!(bytes(OpCode::SEND, Operand::BBB(1, 300, 2)), vec![OpCode::EXT2 as u8, send, 1, 1, 44, 2]);
+        assert_eq!(bytes(OpCode::JMPNOT, Operand::BS(300, 5)), vec![OpCode::EXT1 as u8, OpCode::JMPNOT as u8, 1, 44, 0, 5]);
+    }
+}
@@ -4,14 +4,15 @@ use std::io::Write;
 use std::rc::Rc;
 
 use super::*;
+use super::transformer::PoolValue;
 use super::binfmt::{RiteBinaryHeader, SectionMiscHeader, SectionIrepHeader, IrepRecord};
 
 fn u16_as_be_bytes(value: u16) -> [u8; 2] {
-    unsafe { std::mem::transmute(value.to_be()) }
+    value.to_be_bytes()
 }
 
 fn u32_as_be_bytes(value: u32) -> [u8; 4] {
-    unsafe { std::mem::transmute(value.to_be()) }
+    value.to_be_bytes()
 }
 
 fn sym_as_bytes(values: &HashMap<usize, String>) -> Vec<u8> {
@@ -26,18 +27,40 @@ fn sym_as_bytes(values: &HashMap<usize, String>) -> Vec<u8> {
     bytes
 }
 
-fn strings_as_pool_bytes(values: &HashMap<usize, String>) -> Vec<u8> {
+fn pool_as_bytes(values: &HashMap<usize, PoolValue>) -> Vec<u8> {
     let mut bytes = Vec::new();
     bytes.extend_from_slice(&u16_as_be_bytes(values.len() as u16));
     for idx in 0..values.len() {
-        bytes.push(0); // IREP_TT_STR
-        bytes.extend_from_slice(&u16_as_be_bytes(values.get(&idx).unwrap().len() as u16));
-        bytes.extend_from_slice(values.get(&idx).unwrap().as_bytes());
-        bytes.push(0);
+        match values.get(&idx).unwrap() {
+            PoolValue::Str(value) => {
+                let Ok(len) = u16::try_from(value.len()) else {
+                    panic!("String too long for the pool: {} bytes", value.len());
+                };
+                bytes.push(0); // IREP_TT_STR
+                bytes.extend_from_slice(&u16_as_be_bytes(len));
+                bytes.extend_from_slice(value);
+                bytes.push(0);
+            },
+            PoolValue::Int(value) => match i32::try_from(*value) {
+                Ok(value) => {
+                    bytes.push(1); // IREP_TT_INT32
+                    bytes.extend_from_slice(&value.to_be_bytes());
+                },
+                Err(_) => {
+                    bytes.push(3); // IREP_TT_INT64
+                    bytes.extend_from_slice(&value.to_be_bytes());
+                },
+            },
+            PoolValue::Float(value) => {
+                bytes.push(5); // IREP_TT_FLOAT, in little endian
+                bytes.extend_from_slice(&value.to_le_bytes());
+            },
+        }
     }
     bytes
 }
 
+#[derive(Default)]
 pub struct RitePacker {
     pub buf: Vec<u8>,
 }
@@ -50,29 +73,35 @@ impl RitePacker {
     }
 
     pub fn pack(&mut self, reps: &[Rc<RefCell<transformer::IrepBase>>]) -> Result<(), String> {
-        let mut binheader = RiteBinaryHeader::default();
-        binheader.ident = *b"RITE";
-        binheader.major_version = *b"03";
-        binheader.minor_version = *b"00";
-        binheader.compiler_name = *b"LUNR";
-        binheader.compiler_version = *b"0000";
-        // fill in the size field lator
+        let mut binheader = RiteBinaryHeader {
+            ident: *b"RITE",
+            major_version: *b"03",
+            minor_version: *b"00",
+            compiler_name: *b"LUNR",
+            compiler_version: *b"0000",
+            // fill in the size field lator
+            ..Default::default()
+        };
         let mut binsize = size_of::<RiteBinaryHeader>();
 
-        let mut irepheader = SectionIrepHeader::default();
-        irepheader.ident = *b"IREP";
-        irepheader.rite_version = *b"0300";
-        // fill in the size field lator
+        let mut irepheader = SectionIrepHeader {
+            ident: *b"IREP",
+            rite_version: *b"0300",
+            // fill in the size field lator
+            ..Default::default()
+        };
         let mut secsize = size_of::<SectionIrepHeader>();
 
         let mut ireps = Vec::new();
         for rep in reps {
-            let mut irep = IrepRecord::default();
-            irep.nlocals = u16_as_be_bytes(rep.borrow().locals as u16);
-            irep.nregs = u16_as_be_bytes(rep.borrow().regs as u16);
-            irep.rlen = u16_as_be_bytes(rep.borrow().rep_len as u16);
-            irep.clen = u16_as_be_bytes(rep.borrow().chandlers as u16);
-            // fill in the size, ilen field lator
+            let mut irep = IrepRecord {
+                nlocals: u16_as_be_bytes(rep.borrow().locals as u16),
+                nregs: u16_as_be_bytes(rep.borrow().regs as u16),
+                rlen: u16_as_be_bytes(rep.borrow().rep_len as u16),
+                clen: u16_as_be_bytes(rep.borrow().chandlers as u16),
+                // fill in the size, ilen field lator
+                ..Default::default()
+            };
             let mut insn: Vec<u8> = Vec::new();
 
             for i in rep.borrow().insn.iter() {
@@ -82,7 +111,7 @@ impl RitePacker {
             irep.ilen = u32_as_be_bytes(insn.len() as u32);
 
             let syms = sym_as_bytes(&rep.borrow().syms);
-            let pool = strings_as_pool_bytes(&rep.borrow().pool);
+            let pool = pool_as_bytes(&rep.borrow().pool);
 
             let size = size_of::<IrepRecord>() + insn.len() + syms.len() + pool.len();
             irep.size = u32_as_be_bytes(size as u32);
@@ -94,9 +123,10 @@ impl RitePacker {
         binsize += secsize;
 
 
-        let mut endsection = SectionMiscHeader::default();
-        endsection.ident = *b"END\0";
-        endsection.size = u32_as_be_bytes(size_of::<SectionMiscHeader>() as u32);
+        let endsection = SectionMiscHeader {
+            ident: *b"END\0",
+            size: u32_as_be_bytes(size_of::<SectionMiscHeader>() as u32),
+        };
         binsize += size_of::<SectionMiscHeader>();
 
         binheader.size = u32_as_be_bytes(binsize as u32);
@@ -3,8 +3,17 @@ use std::collections::HashMap;
 use std::rc::Rc;
 
 use crate::rite::bytecode::*;
+use crate::lua::errors::CompileError;
 use crate::lua::lunarir::*;
 
+// Literals of the pool, packed as IREP_TT_STR, IREP_TT_INT64 and IREP_TT_FLOAT
+#[derive(Debug, Clone)]
+pub enum PoolValue {
+    Str(Vec<u8>),
+    Int(i64),
+    Float(f64),
+}
+
 #[derive(Debug)]
 pub struct IrepBase {
     pub locals: usize,
@@ -12,43 +21,242 @@ pub struct IrepBase {
     pub rep_len: usize,
     pub chandlers: usize, // TODO: fixed to 0; not yet implemented
     pub syms: HashMap<usize, String>,
-    pub pool: HashMap<usize, String>,
+    pub pool: HashMap<usize, PoolValue>,
     pub insn: Vec<Bytecode>,
 
     pub parent: Option<Rc<RefCell<IrepBase>>>,
+
+    // register allocation state while transforming
+    pub sp: usize,
+    pub local_top: usize,
+    pub lvars: HashMap<usize, usize>,
+    // label => index of the instruction it points to
+    pub labels: HashMap<usize, usize>,
+    // (index of the jump instruction, label) to be patched
+    pub jumps: Vec<(usize, usize)>,
 }
 
 impl IrepBase {
     pub fn new() -> Rc<RefCell<Self>>{
         let base = IrepBase {
-            locals: 0,
-            regs: 0,
+            // R0 is always self
+            locals: 1,
+            regs: 1,
             rep_len: 0,
             chandlers: 0,
             syms: HashMap::new(),
             pool: HashMap::new(),
             insn: Vec::new(),
             parent: None,
+            sp: 1,
+            local_top: 1,
+            lvars: HashMap::new(),
+            labels: HashMap::new(),
+            jumps: Vec::new(),
         };
         Rc::new(RefCell::new(base))
     }
+
+    pub fn push_insn(&mut self, op: OpCode, operand: Operand) {
+        self.insn.push(Bytecode::new(op, operand));
+    }
+
+    // marks the register as used so that nregs covers it
+    pub fn touch_reg(&mut self, reg: usize) {
+        if reg + 1 > self.regs {
+            self.regs = reg + 1;
+        }
+    }
+
+    pub fn push_reg(&mut self) -> usize {
+        let reg = self.sp;
+        self.sp += 1;
+        self.touch_reg(reg);
+        reg
+    }
+
+    pub fn pop_reg(&mut self) -> usize {
+        if self.sp <= self.local_top {
+            panic!("Stack underflow: no temporary value to pop");
+        }
+        self.sp -= 1;
+        self.sp
+    }
+
+    pub fn bind_local(&mut self, idx: usize) -> usize {
+        let reg = self.local_top;
+        if reg >= self.sp {
+            panic!("No value on the stack to bind local {}", idx);
+        }
+        self.local_top += 1;
+        self.lvars.insert(idx, reg);
+        if reg + 1 > self.locals {
+            self.locals = reg + 1;
+        }
+        reg
+    }
+
+    // later locals take the registers again; nregs keeps the maximum
+    pub fn free_locals(&mut self, idx: usize) {
+        let reg = self.local_reg(idx);
+        if self.sp != self.local_top {
+            panic!("Values left on the stack when freeing local {}", idx);
+        }
+        self.lvars.retain(|_, bound| *bound < reg);
+        self.local_top = reg;
+        self.sp = reg;
+    }
+
+    // registers are B operands and symbols, pool entries and children are
+    // addressed by 16-bit operands
+    pub fn check_limits(&self) -> Result<(), CompileError> {
+        if self.regs > 0x100 {
+            return Err(CompileError::new(format!("function or expression needs too many registers: {}", self.regs), None));
+        }
+        let limit = u16::MAX as usize;
+        if self.syms.len() > limit {
+            return Err(CompileError::new(format!("too many symbols in a function: {}", self.syms.len()), None));
+        }
+        if self.pool.len() > limit {
+            return Err(CompileError::new(format!("too many literals in a function: {}", self.pool.len()), None));
+        }
+        if self.rep_len > limit {
+            return Err(CompileError::new(format!("too many nested functions: {}", self.rep_len), None));
+        }
+        Ok(())
+    }
+
+    pub fn push_jump(&mut self, op: OpCode, reg: Option<usize>, label: usize) {
+        let operand = match reg {
+            Some(reg) => Operand::BS(reg as u16, 0),
+            None => Operand::S(0),
+        };
+        self.jumps.push((self.insn.len(), label));
+        self.push_insn(op, operand);
+    }
+
+    // jump offsets are relative to the end of the jump instruction and
+    // must fit in 16 bits, as there is no longer jump
+    pub fn resolve_jumps(&mut self) -> Result<(), CompileError> {
+        let mut addrs = Vec::with_capacity(self.insn.len() + 1);
+        let mut addr = 0;
+        for insn in self.insn.iter() {
+            addrs.push(addr);
+            addr += insn.to_bytes_vec().len();
+        }
+        addrs.push(addr);
+
+        for (idx, label) in self.jumps.drain(..) {
+            let target = match self.labels.get(&label) {
+                Some(target) => addrs[*target] as isize,
+                None => return Err(CompileError::new(format!("undefined label: {}", label), None)),
+            };
+            let offset = target - addrs[idx + 1] as isize;
+            if offset < i16::MIN as isize || offset > i16::MAX as isize {
+                return Err(CompileError::new(format!("control structure too long: jump of {} bytes", offset), None));
+            }
+            let offset = offset as i16 as u16;
+            self.insn[idx].operand = match self.insn[idx].operand {
+                Operand::BS(reg, _) => Operand::BS(reg, offset),
+                Operand::S(_) => Operand::S(offset),
+                operand => panic!("Invalid jump operand: {:?}", operand),
+            };
+        }
+        Ok(())
+    }
+
+    pub fn local_reg(&self, idx: usize) -> usize {
+        match self.lvars.get(&idx) {
+            Some(reg) => *reg,
+            None => panic!("Unknown local slot: {}", idx),
+        }
+    }
+
+    // the register of a local slot in the irep `depth` levels above the parent
+    pub fn upvar_reg(&self, idx: usize, depth: usize) -> usize {
+        let mut irep = self.parent.clone();
+        for _ in 0..depth {
+            irep = irep.and_then(|irep| irep.borrow().parent.clone());
+        }
+        match irep {
+            Some(irep) => irep.borrow().local_reg(idx),
+            None => panic!("No enclosing irep for upvar depth {}", depth),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum CallKind {
+    // sends the symbol to self
+    Function { sym: usize },
+    // sends the symbol to the receiver
+    Method { sym: usize },
+    // calls the receiver, or sends the symbol to self when it is nil
+    Global { sym: usize, call: usize },
+    // calls the function above the receiver with it as self, or sends the
+    // symbol to the receiver when there is no function
+    SelfMethod { sym: usize, call: usize },
 }
 
 #[derive(Debug, Clone, Copy)]
 pub enum TransformState {
     Top,
-    InFor {
-        reg: usize,
-        sym: usize,
-    },
     InFuncall {
-        sym: usize,
+        kind: CallKind,
         reg: usize,
-        argsize: usize,
+        // arguments are passed as one Array
+        splat: bool,
     },
 }
 
-pub fn transform(lunar_ir: &[LunarIR]) -> Vec<Rc<RefCell<IrepBase>>> {
+// passed as argc when the arguments are packed in an Array
+const CALL_MAXARGS: usize = 15;
+
+fn load_value(irep: &mut IrepBase, reg: usize, value: &LunarValue) {
+    match value {
+        LunarValue::Nil => {
+            irep.push_insn(OpCode::LOADNIL, Operand::B(reg as u16));
+        },
+        LunarValue::Boolean(b) => {
+            if *b {
+                irep.push_insn(OpCode::LOADT, Operand::B(reg as u16));
+            } else {
+                irep.push_insn(OpCode::LOADF, Operand::B(reg as u16));
+            }
+        },
+        LunarValue::Integer(n) => {
+            let n = i32::try_from(*n).unwrap_or_else(|_| panic!("Integer not in the pool: {}", n));
+            let reg = reg as u16;
+            match n {
+                -1 => irep.push_insn(OpCode::LOADI__1, Operand::B(reg)),
+                n @ 0..=7 => {
+                    let op = [
+                        OpCode::LOADI_0, OpCode::LOADI_1, OpCode::LOADI_2, OpCode::LOADI_3,
+                        OpCode::LOADI_4, OpCode::LOADI_5, OpCode::LOADI_6, OpCode::LOADI_7,
+                    ][n as usize];
+                    irep.push_insn(op, Operand::B(reg));
+                },
+                n @ 8..=0xff => irep.push_insn(OpCode::LOADI, Operand::BB(reg, n as u16)),
+                n @ -0xff..=-2 => irep.push_insn(OpCode::LOADINEG, Operand::BB(reg, -n as u16)),
+                n if i16::try_from(n).is_ok() => {
+                    irep.push_insn(OpCode::LOADI16, Operand::BS(reg, n as i16 as u16));
+                },
+                n => {
+                    let n = n as u32;
+                    irep.push_insn(OpCode::LOADI32, Operand::BSS(reg, (n >> 16) as u16, n as u16));
+                },
+            }
+        },
+        LunarValue::Literal(pool_idx) => {
+            irep.push_insn(OpCode::LOADL, Operand::BB(reg as u16, *pool_idx as u16));
+        },
+        LunarValue::String(pool_idx) => {
+            irep.push_insn(OpCode::STRING, Operand::BB(reg as u16, *pool_idx as u16));
+        },
+    }
+}
+
+pub fn transform(lunar_ir: &[LunarIR]) -> Result<Vec<Rc<RefCell<IrepBase>>>, CompileError> {
     let mut reps = Vec::new();
     let mut current: Rc<RefCell<IrepBase>> = IrepBase::new();
     reps.push(current.clone());
@@ -71,172 +279,380 @@ pub fn transform(lunar_ir: &[LunarIR]) -> Vec<Rc<RefCell<IrepBase>>> {
             },
             LunarIR::ChunkEnd => {
                 let current_ = current.clone();
-                let old = current_.borrow_mut();
+                let mut old = current_.borrow_mut();
+                old.resolve_jumps()?;
                 if let Some(p) = old.parent.clone() {
                     current = p;
                 }
                 state = old_states.pop().unwrap();
             },
-            LunarIR::Local(_) => todo!(),
-            LunarIR::ForStart(sym) => {
-                old_states.push(state);
-                state = TransformState::InFor { reg: 0, sym: *sym };
-            },
-            LunarIR::ForParam(start, end, step) => {
-                if let TransformState::InFor{ reg: _, sym } = state {
-                    let mut pushed = 0;
-                    let mut first_reg = -1;
-                    for i in (*start..=*end).step_by(*step) {
-                        current.borrow_mut().regs += 1;
-
-                        let reg = current.borrow().regs;
-                        current.borrow_mut().insn.push(Bytecode {
-                            op: OpCode::LOADI,
-                            operand: Operand::BB(reg as u8, i as u8)
-                        });
-
-                        pushed += 1;
-
-                        if first_reg == -1 {
-                            first_reg = reg as isize;
-                        }
-                    }
-                    current.borrow_mut().insn.push(Bytecode {
-                        op: OpCode::ARRAY,
-                        operand: Operand::BB(first_reg as u8, pushed as u8)
-                    });
-
-                    state = TransformState::InFor { reg: first_reg as usize, sym };
-                } else {
-                    panic!("Invalid state: expected InFor context");
+            LunarIR::Local(idx) => {
+                current.borrow_mut().bind_local(*idx);
+            },
+            LunarIR::FreeLocals(idx) => {
+                current.borrow_mut().free_locals(*idx);
+            },
+            LunarIR::GetLocal(idx) => {
+                let mut irep = current.borrow_mut();
+                let src = irep.local_reg(*idx);
+                let dst = irep.push_reg();
+                irep.push_insn(OpCode::MOVE, Operand::BB(dst as u16, src as u16));
+            },
+            LunarIR::SetLocal(idx) => {
+                let mut irep = current.borrow_mut();
+                let dst = irep.local_reg(*idx);
+                let src = irep.pop_reg();
+                irep.push_insn(OpCode::MOVE, Operand::BB(dst as u16, src as u16));
+            },
+            LunarIR::GetUpvar(idx, depth) => {
+                let mut irep = current.borrow_mut();
+                let src = irep.upvar_reg(*idx, *depth);
+                let dst = irep.push_reg();
+                irep.push_insn(OpCode::GETUPVAR, Operand::BBB(dst as u16, src as u16, *depth as u8));
+            },
+            LunarIR::SetUpvar(idx, depth) => {
+                let mut irep = current.borrow_mut();
+                let dst = irep.upvar_reg(*idx, *depth);
+                let src = irep.pop_reg();
+                irep.push_insn(OpCode::SETUPVAR, Operand::BBB(src as u16, dst as u16, *depth as u8));
+            },
+            LunarIR::GetGlobal(sym) => {
+                let mut irep = current.borrow_mut();
+                let dst = irep.push_reg();
+                irep.push_insn(OpCode::GETGV, Operand::BB(dst as u16, *sym as u16));
+            },
+            LunarIR::GetConst(sym) => {
+                let mut irep = current.borrow_mut();
+                let dst = irep.push_reg();
+                irep.push_insn(OpCode::GETCONST, Operand::BB(dst as u16, *sym as u16));
+            },
+            LunarIR::SetGlobal(sym) => {
+                let mut irep = current.borrow_mut();
+                let src = irep.pop_reg();
+                irep.push_insn(OpCode::SETGV, Operand::BB(src as u16, *sym as u16));
+            },
+            LunarIR::InitGlobals(sym) => {
+                let mut irep = current.borrow_mut();
+                let reg = irep.push_reg();
+                irep.push_insn(OpCode::GETGV, Operand::BB(reg as u16, *sym as u16));
+                // skips HASH and SETGV below (3 bytes each)
+                irep.push_insn(OpCode::JMPIF, Operand::BS(reg as u16, 6));
+                irep.push_insn(OpCode::HASH, Operand::BB(reg as u16, 0));
+                irep.push_insn(OpCode::SETGV, Operand::BB(reg as u16, *sym as u16));
+                irep.pop_reg();
+            },
+            LunarIR::GetIndex => {
+                let mut irep = current.borrow_mut();
+                irep.pop_reg();
+                let reg = irep.pop_reg();
+                irep.push_insn(OpCode::GETIDX, Operand::B(reg as u16));
+                irep.push_reg();
+            },
+            LunarIR::SetIndex(delete) => {
+                let mut irep = current.borrow_mut();
+                irep.pop_reg();
+                irep.pop_reg();
+                let reg = irep.pop_reg();
+                // JMPNIL value, L1; SETIDX reg; JMP L2; L1: SEND reg, delete, 1; L2:
+                let setidx = Bytecode::new(OpCode::SETIDX, Operand::B(reg as u16));
+                let send = Bytecode::new(OpCode::SEND, Operand::BBB(reg as u16, *delete as u16, 1));
+                let skip_send = Bytecode::new(OpCode::JMP, Operand::S(send.to_bytes_vec().len() as u16));
+                let skip_setidx = setidx.to_bytes_vec().len() + skip_send.to_bytes_vec().len();
+                irep.push_insn(OpCode::JMPNIL, Operand::BS((reg + 2) as u16, skip_setidx as u16));
+                irep.insn.push(setidx);
+                irep.insn.push(skip_send);
+                irep.insn.push(send);
+            },
+            LunarIR::Label(label) => {
+                let mut irep = current.borrow_mut();
+                let idx = irep.insn.len();
+                irep.labels.insert(*label, idx);
+            },
+            LunarIR::Jump(label) => {
+                current.borrow_mut().push_jump(OpCode::JMP, None, *label);
+            },
+            LunarIR::JumpIf(label) => {
+                let mut irep = current.borrow_mut();
+                let reg = irep.sp - 1;
+                irep.push_jump(OpCode::JMPIF, Some(reg), *label);
+            },
+            LunarIR::JumpNot(label) => {
+                let mut irep = current.borrow_mut();
+                let reg = irep.sp - 1;
+                irep.push_jump(OpCode::JMPNOT, Some(reg), *label);
+            },
+            LunarIR::JumpNil(label) => {
+                let mut irep = current.borrow_mut();
+                let reg = irep.sp - 1;
+                irep.push_jump(OpCode::JMPNIL, Some(reg), *label);
+            },
+            LunarIR::Unpack(n) => {
+                let mut irep = current.borrow_mut();
+                let reg = irep.pop_reg();
+                for _ in 0..*n {
+                    irep.push_reg();
+                }
+                // AREF of a non-Array gives the value itself for index 0;
+                // the source register is overwritten last
+                for i in (0..*n).rev() {
+                    irep.push_insn(OpCode::AREF, Operand::BBB((reg + i) as u16, reg as u16, i as u8));
                 }
             },
-            LunarIR::ForEnd => {
-                if let TransformState::InFor{ reg, sym } = state {
-                    current.borrow_mut().insn.push(Bytecode {
-                        op: OpCode::SENDB,
-                        operand: Operand::BBB(reg as u8, sym as u8, 0u8)
-                    });
-
-                    state = old_states.pop().unwrap();
+            LunarIR::Array(n) => {
+                let mut irep = current.borrow_mut();
+                for _ in 0..*n {
+                    irep.pop_reg();
+                }
+                let reg = irep.push_reg();
+                irep.push_insn(OpCode::ARRAY, Operand::BB(reg as u16, *n as u16));
+            },
+            LunarIR::Pack(n) => {
+                let mut irep = current.borrow_mut();
+                irep.pop_reg();
+                for _ in 0..*n {
+                    irep.pop_reg();
+                }
+                let reg = irep.push_reg();
+                if *n == 0 {
+                    // a copy, as the Array may be a local such as `...`
+                    irep.push_insn(OpCode::ARYSPLAT, Operand::B(reg as u16));
                 } else {
-                    panic!("Invalid state: expected InFor context");
+                    irep.push_insn(OpCode::ARRAY, Operand::BB(reg as u16, *n as u16));
+                    if *n > 1 {
+                        irep.push_insn(OpCode::MOVE, Operand::BB((reg + 1) as u16, (reg + n) as u16));
+                    }
+                    irep.push_insn(OpCode::ARYCAT, Operand::B(reg as u16));
                 }
             },
+            LunarIR::NewTable(n) => {
+                let mut irep = current.borrow_mut();
+                for _ in 0..*n * 2 {
+                    irep.pop_reg();
+                }
+                let reg = irep.push_reg();
+                irep.push_insn(OpCode::HASH, Operand::BB(reg as u16, *n as u16));
+            },
+            LunarIR::TableAdd(n) => {
+                let mut irep = current.borrow_mut();
+                for _ in 0..*n * 2 {
+                    irep.pop_reg();
+                }
+                let reg = irep.sp - 1;
+                irep.push_insn(OpCode::HASHADD, Operand::BB(reg as u16, *n as u16));
+            },
+            LunarIR::Copy(n) => {
+                let mut irep = current.borrow_mut();
+                let src = irep.sp - 1 - *n;
+                let dst = irep.push_reg();
+                irep.push_insn(OpCode::MOVE, Operand::BB(dst as u16, src as u16));
+            },
+            LunarIR::Value(lunar_value) => {
+                let mut irep = current.borrow_mut();
+                let reg = irep.push_reg();
+                load_value(&mut irep, reg, lunar_value);
+            },
+            LunarIR::BinOp(op) => {
+                let mut irep = current.borrow_mut();
+                irep.pop_reg();
+                let reg = irep.sp - 1;
+                let op = match op {
+                    BinOp::Add => OpCode::ADD,
+                    BinOp::Sub => OpCode::SUB,
+                    BinOp::Mul => OpCode::MUL,
+                    BinOp::Div => OpCode::DIV,
+                    BinOp::Eq => OpCode::EQ,
+                    BinOp::Lt => OpCode::LT,
+                    BinOp::Le => OpCode::LE,
+                    BinOp::Gt => OpCode::GT,
+                    BinOp::Ge => OpCode::GE,
+                    BinOp::Concat => OpCode::STRCAT,
+                };
+                irep.push_insn(op, Operand::B(reg as u16));
+            },
+            LunarIR::AddI(n) => {
+                let mut irep = current.borrow_mut();
+                let reg = irep.sp - 1;
+                irep.push_insn(OpCode::ADDI, Operand::BB(reg as u16, *n as u16));
+            },
+            LunarIR::SubI(n) => {
+                let mut irep = current.borrow_mut();
+                let reg = irep.sp - 1;
+                irep.push_insn(OpCode::SUBI, Operand::BB(reg as u16, *n as u16));
+            },
+            LunarIR::Send(sym, argsize) => {
+                let mut irep = current.borrow_mut();
+                let reg = irep.sp - argsize - 1;
+                irep.push_insn(OpCode::SEND, Operand::BBB(reg as u16, *sym as u16, *argsize as u8));
+                // the block argument slot
+                irep.touch_reg(reg + argsize + 1);
+                irep.sp = reg + 1;
+            },
+            LunarIR::Store(n) => {
+                let mut irep = current.borrow_mut();
+                let src = irep.pop_reg();
+                let dst = src - 1 - *n;
+                irep.push_insn(OpCode::MOVE, Operand::BB(dst as u16, src as u16));
+            },
+            LunarIR::Pop => {
+                current.borrow_mut().pop_reg();
+            },
             LunarIR::Enter(eval) => {
-                current.borrow_mut().insn.push(Bytecode {
-                    op: OpCode::ENTER,
-                    operand: Operand::W(*eval),
-                });
-                // TODO: get argsize from ENTER valus
-                current.borrow_mut().regs += 1;
-                current.borrow_mut().locals += 1;
-                // TODO: what is this?
-                current.borrow_mut().locals += 2;
-                // set regs = locals + 1
-                current.borrow_mut().regs += 1;
+                let mut irep = current.borrow_mut();
+                irep.push_insn(OpCode::ENTER, Operand::W(*eval));
+                // m1 + o + r + m2
+                let argsize = ((*eval >> 18) & 0x1f)
+                    + ((*eval >> 13) & 0x1f)
+                    + ((*eval >> 12) & 0x1)
+                    + ((*eval >> 7) & 0x1f);
+                let argsize = argsize as usize;
+                for i in 0..argsize {
+                    irep.lvars.insert(i, i + 1);
+                }
+                // arguments and the block argument follow self
+                irep.sp = argsize + 2;
+                irep.local_top = argsize + 2;
+                irep.locals = argsize + 2;
+                irep.touch_reg(argsize + 1);
             },
             LunarIR::StoreSym(idx, name) => {
                 current.borrow_mut().syms.insert(*idx, name.clone());
             },
+            LunarIR::Lambda(rep) => {
+                let mut irep = current.borrow_mut();
+                let dst = irep.push_reg();
+                irep.push_insn(OpCode::LAMBDA, Operand::BB(dst as u16, *rep as u16));
+            },
+            LunarIR::Return => {
+                let mut irep = current.borrow_mut();
+                let reg = irep.pop_reg();
+                irep.push_insn(OpCode::RETURN, Operand::B(reg as u16));
+            },
             LunarIR::FunctionCallStart(sym) => {
                 old_states.push(state);
-                current.borrow_mut().regs += 1;
-                let reg = current.borrow().regs;
+                let reg = current.borrow_mut().push_reg();
                 state = TransformState::InFuncall {
-                    sym: *sym,
+                    kind: CallKind::Function { sym: *sym },
                     reg,
-                    argsize: 0,
+                    splat: false,
                 };
             },
-            LunarIR::FunctionCallArg(_, lunar_value) => {
-                if let TransformState::InFuncall{ sym, reg, argsize } = state {
-                    current.borrow_mut().regs += 1;
-                    let arg_reg = current.borrow().regs;
-                    match lunar_value {
-                        LunarValue::Nil => {
-                            current.borrow_mut().insn.push(Bytecode {
-                                op: OpCode::LOADNIL,
-                                operand: Operand::B(arg_reg as u8),
-                            });
-                        },
-                        LunarValue::Boolean(b) => {
-                            if *b {
-                                current.borrow_mut().insn.push(Bytecode {
-                                    op: OpCode::LOADT,
-                                    operand: Operand::B(arg_reg as u8),
-                                });
-                            } else {
-                                current.borrow_mut().insn.push(Bytecode {
-                                    op: OpCode::LOADF,
-                                    operand: Operand::B(arg_reg as u8),
-                                });
-                            }
-                        },
-                        LunarValue::Number(n) => {
-                            current.borrow_mut().insn.push(Bytecode {
-                                op: OpCode::LOADI,
-                                operand: Operand::BB(arg_reg as u8, *n as u8),
-                            });
-                        },
-                        LunarValue::String(pool_idx) => {
-                            current.borrow_mut().insn.push(Bytecode {
-                                op: OpCode::STRING,
-                                operand: Operand::BB(arg_reg as u8, *pool_idx as u8),
-                            });
-                        },
-                    }
-                    state = TransformState::InFuncall { sym, reg, argsize: argsize + 1 };
-                } else {
-                    panic!("Invalid state: expected InFuncall context");
+            LunarIR::MethodCallStart(sym) => {
+                old_states.push(state);
+                let reg = current.borrow().sp - 1;
+                state = TransformState::InFuncall {
+                    kind: CallKind::Method { sym: *sym },
+                    reg,
+                    splat: false,
+                };
+            },
+            LunarIR::GlobalCallStart(sym, call) => {
+                old_states.push(state);
+                let reg = current.borrow().sp - 1;
+                state = TransformState::InFuncall {
+                    kind: CallKind::Global { sym: *sym, call: *call },
+                    reg,
+                    splat: false,
+                };
+            },
+            LunarIR::SelfCallStart(sym, call) => {
+                old_states.push(state);
+                let reg = current.borrow().sp - 3;
+                state = TransformState::InFuncall {
+                    kind: CallKind::SelfMethod { sym: *sym, call: *call },
+                    reg,
+                    splat: false,
+                };
+            },
+            LunarIR::SplatArgs => {
+                match &mut state {
+                    TransformState::InFuncall { splat, .. } => *splat = true,
+                    TransformState::Top => panic!("Invalid state: expected InFuncall context"),
                 }
             },
             LunarIR::FunctionCallEnd => {
-                if let TransformState::InFuncall{ sym, reg, argsize } = state {
-                    current.borrow_mut().insn.push(Bytecode {
-                        op: OpCode::SSEND,
-                        operand: Operand::BBB(reg as u8, sym as u8, argsize as u8)
-                    });
-                    state = old_states.pop().unwrap();
-                } else {
-                    panic!("Invalid state: expected InFuncall context");
+                let mut irep = current.borrow_mut();
+                let (kind, reg, splat) = match state {
+                    TransformState::InFuncall { kind, reg, splat } => (kind, reg, splat),
+                    TransformState::Top => panic!("Invalid state: expected InFuncall context"),
+                };
+                let argsize = irep.sp - reg - 1;
+                let argc = if splat { CALL_MAXARGS } else { argsize };
+                let operand = |sym: usize| Operand::BBB(reg as u16, sym as u16, argc as u8);
+                match kind {
+                    CallKind::Function { sym } => {
+                        irep.push_insn(OpCode::SSEND, operand(sym));
+                    },
+                    CallKind::Method { sym } => {
+                        irep.push_insn(OpCode::SEND, operand(sym));
+                    },
+                    CallKind::Global { sym, call } => {
+                        // JMPNIL reg, L1; SEND reg, call; JMP L2; L1: SSEND reg, sym; L2:
+                        let send = Bytecode::new(OpCode::SEND, operand(call));
+                        let ssend = Bytecode::new(OpCode::SSEND, operand(sym));
+                        let skip_ssend = Bytecode::new(OpCode::JMP, Operand::S(ssend.to_bytes_vec().len() as u16));
+                        let skip_send = send.to_bytes_vec().len() + skip_ssend.to_bytes_vec().len();
+                        irep.push_insn(OpCode::JMPNIL, Operand::BS(reg as u16, skip_send as u16));
+                        irep.insn.push(send);
+                        irep.insn.push(skip_ssend);
+                        irep.insn.push(ssend);
+                    },
+                    CallKind::SelfMethod { sym, call } => {
+                        // the function at reg+1 and the receiver at reg+2
+                        // JMPNOT reg+1, L1; SEND reg+1, call; MOVE reg, reg+1; JMP L2;
+                        // L1: SEND reg+2, sym; MOVE reg, reg+2; L2:
+                        let argsize = argsize - 2;
+                        let argc = if splat { CALL_MAXARGS } else { argsize };
+                        let (func, recv) = (reg + 1, reg + 2);
+                        let mut call_insns = Vec::new();
+                        let call_argc = if splat {
+                            // the receiver goes in front of the Array of arguments
+                            call_insns.push(Bytecode::new(OpCode::ARRAY, Operand::BB(recv as u16, 1)));
+                            call_insns.push(Bytecode::new(OpCode::ARYCAT, Operand::B(recv as u16)));
+                            CALL_MAXARGS
+                        } else if argsize + 1 >= CALL_MAXARGS {
+                            call_insns.push(Bytecode::new(OpCode::ARRAY, Operand::BB(recv as u16, (argsize + 1) as u16)));
+                            CALL_MAXARGS
+                        } else {
+                            argsize + 1
+                        };
+                        call_insns.push(Bytecode::new(OpCode::SEND, Operand::BBB(func as u16, call as u16, call_argc as u8)));
+                        call_insns.push(Bytecode::new(OpCode::MOVE, Operand::BB(reg as u16, func as u16)));
+                        let send_insns = [
+                            Bytecode::new(OpCode::SEND, Operand::BBB(recv as u16, sym as u16, argc as u8)),
+                            Bytecode::new(OpCode::MOVE, Operand::BB(reg as u16, recv as u16)),
+                        ];
+                        let len = |insns: &[Bytecode]| insns.iter().map(|insn| insn.to_bytes_vec().len()).sum::<usize>();
+                        let skip_send = Bytecode::new(OpCode::JMP, Operand::S(len(&send_insns) as u16));
+                        let skip_call = len(&call_insns) + skip_send.to_bytes_vec().len();
+                        irep.push_insn(OpCode::JMPNOT, Operand::BS(func as u16, skip_call as u16));
+                        irep.insn.extend(call_insns);
+                        irep.insn.push(skip_send);
+                        irep.insn.extend(send_insns);
+                    },
                 }
+                // the block argument slot
+                irep.touch_reg(reg + argsize + 1);
+                irep.sp = reg + 1;
+                state = old_states.pop().unwrap();
             },
             LunarIR::PoolString(idx, value) => {
-                current.borrow_mut().pool.insert(*idx, value.clone());
-            },
-            LunarIR::Block(b) => {
-                if let TransformState::InFor{ reg, sym: _ } = state {
-                    current.borrow_mut().insn.push(Bytecode {
-                        op: OpCode::BLOCK,
-                        operand: Operand::BB(reg as u8 + 1, *b as u8)
-                    });
-                } else {
-                    current.borrow_mut().regs += 1;
-                    let reg = current.borrow().regs;
-                    current.borrow_mut().insn.push(Bytecode {
-                        op: OpCode::BLOCK,
-                        operand: Operand::BB(reg as u8, *b as u8)
-                    });
-                }
+                current.borrow_mut().pool.insert(*idx, PoolValue::Str(value.clone()));
+            },
+            LunarIR::PoolInteger(idx, value) => {
+                current.borrow_mut().pool.insert(*idx, PoolValue::Int(*value));
+            },
+            LunarIR::PoolFloat(idx, value) => {
+                current.borrow_mut().pool.insert(*idx, PoolValue::Float(*value));
             },
             LunarIR::NoReturn => {
-                current.borrow_mut().regs += 1;
-                let reg = current.borrow().regs;
-                current.borrow_mut().insn.push(Bytecode {
-                    op: OpCode::LOADNIL,
-                    operand: Operand::B(reg as u8),
-                });
-                current.borrow_mut().insn.push(Bytecode {
-                    op: OpCode::RETURN,
-                    operand: Operand::B(reg as u8),
-                });
+                let mut irep = current.borrow_mut();
+                let reg = irep.push_reg();
+                irep.push_insn(OpCode::LOADNIL, Operand::B(reg as u16));
+                irep.push_insn(OpCode::RETURN, Operand::B(reg as u16));
+                irep.pop_reg();
             },
             LunarIR::Stop => {
-                current.borrow_mut().insn.push(Bytecode {
-                    op: OpCode::STOP,
-                    operand: Operand::Z,
-                });
+                current.borrow_mut().push_insn(OpCode::STOP, Operand::Z);
             },
         }
     }
@@ -250,5 +666,111 @@ pub fn transform(lunar_ir: &[LunarIR]) -> Vec<Rc<RefCell<IrepBase>>> {
             break;
         }
     }
-    reps
-}
\ No newline at end of file
+
+    for rep in reps.iter() {
+        rep.borrow().check_limits()?;
+    }
+    Ok(reps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lua::loader::load_string;
+    use crate::lua::walker::Walker;
+
+    fn compile(source: &str) -> Result<Vec<Rc<RefCell<IrepBase>>>, CompileError> {
+        let program = load_string(source).unwrap();
+        let mut walker = Walker::new();
+        walker.walk(&program.block).unwrap();
+        transform(&walker.msg_stack)
+    }
+
+    fn insns(source: &str, irep: usize) -> Vec<Bytecode> {
+        compile(source).unwrap()[irep].borrow().insn.clone()
+    }
+
+    // the register loaded by the first LOADI_n of the value
+    fn loaded_reg(insns: &[Bytecode], value: u8) -> u16 {
+        let op = OpCode::LOADI_0 as u8 + value;
+        insns.iter().find_map(|insn| match insn.operand {
+            Operand::B(reg) if insn.op as u8 == op => Some(reg),
+            _ => None,
+        }).unwrap()
+    }
+
+    #[test]
+    fn registers_of_a_block_are_reused_after_it() {
+        let code = insns("do local a = 5 end\nlocal b = 6", 0);
+        assert_eq!(loaded_reg(&code, 5), loaded_reg(&code, 6));
+
+        // a local captured by a closure keeps its register
+        let code = insns("do local c = 7 f = function() return c end end\nlocal d = 3", 0);
+        assert_ne!(loaded_reg(&code, 7), loaded_reg(&code, 3));
+    }
+
+    // (op, register, depth) of the GETUPVAR and SETUPVAR instructions
+    fn upvars(insns: &[Bytecode]) -> Vec<(OpCode, u16, u16)> {
+        insns.iter().filter_map(|insn| match insn.operand {
+            Operand::BBB(_, reg, depth) if matches!(insn.op, OpCode::GETUPVAR | OpCode::SETUPVAR) => {
+                Some((insn.op, reg, depth as u16))
+            },
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn upvars_count_the_levels_between_the_functions() {
+        let source = "local x = 1\nlocal function f()\n  x = 3\n  return function() x = 2 return x end\nend";
+        // x is in register 2 of the main chunk, after the rest argument
+        assert_eq!(upvars(&insns(source, 1)), vec![(OpCode::SETUPVAR, 2, 0)]);
+        assert_eq!(upvars(&insns(source, 2)), vec![(OpCode::SETUPVAR, 2, 1), (OpCode::GETUPVAR, 2, 1)]);
+    }
+
+    #[test]
+    fn captured_loop_locals_are_boxed_per_iteration() {
+        let source = "for i = 1, 3 do\n  f = function() return i end\nend";
+        let main = insns(source, 0);
+        assert!(main.iter().any(|insn| matches!((insn.op, insn.operand), (OpCode::ARRAY, Operand::BB(_, 1)))));
+        // the lambda called on each iteration copies the box of i into
+        // its frame, and the closure reads it from there
+        let thunk = insns(source, 1);
+        assert_eq!(thunk[0].op, OpCode::ENTER);
+        assert_eq!(upvars(&thunk).len(), 1);
+        assert_eq!(upvars(&insns(source, 2)), vec![(OpCode::GETUPVAR, 2, 0)]);
+    }
+
+    // (index of the jump, index of its target) of each jump
+    fn jumps(insns: &[Bytecode]) -> Vec<(usize, usize)> {
+        let mut addrs = vec![0];
+        for insn in insns.iter() {
+            addrs.push(addrs.last().unwrap() + insn.to_bytes_vec().len());
+        }
+        insns.iter().enumerate().filter_map(|(idx, insn)| {
+            let offset = match insn.operand {
+                Operand::BS(_, offset) if insn.op != OpCode::LOADI => offset,
+                Operand::S(offset) => offset,
+                _ => return None,
+            };
+            let target = addrs[idx + 1] as isize + offset as i16 as isize;
+            Some((idx, addrs.iter().position(|&addr| addr as isize == target).unwrap()))
+        }).collect()
+    }
+
+    #[test]
+    fn jumps_land_on_their_labels() {
+        let code = insns("if x then y = 1 end\nz = 2\nwhile x do y = 1 end", 0);
+        // JMPNOT past `y = 1` to `z = 2`; JMPNOT out of the loop and JMP
+        // back to the test of x
+        assert_eq!(jumps(&code), vec![(2, 5), (8, 12), (11, 7)]);
+        assert_eq!(code[5].op, OpCode::LOADI_2);
+        assert_eq!(code[7].op, OpCode::GETGV);
+    }
+
+    #[test]
+    fn jumps_out_of_range_are_errors() {
+        let source = format!("if x then\n{}end", "y = 1\n".repeat(7000));
+        let error = compile(&source).unwrap_err();
+        assert_eq!(error.message, "control structure too long: jump of 35000 bytes");
+    }
+}